@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Replaying one side of a transfer, so that only the other one is measured
+
+use std::io::{self, Read, Write};
+use zmodem::{Encoding, Frame, Header, Zrinit};
+
+// Subpackets sent before an acknowledgement, by default:
+const PER_ACK: u32 = 10;
+
+/// Replays what one side of a transfer sends, and writes what the other
+/// side sends to `W`.
+pub struct Replay<'a, W>(pub &'a [u8], pub W);
+
+impl<W> Read for Replay<'_, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<W: Write> Write for Replay<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.1.flush()
+    }
+}
+
+/// Returns what the receiver sends for a transfer of `len` bytes: an
+/// acknowledgement for each window, and then for the end of the file.
+pub fn receiver(len: u32) -> Vec<u8> {
+    let mut input = vec![];
+    let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO | Zrinit::CANFDX;
+    Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+    Header::new(Encoding::ZHEX, Frame::ZRPOS)
+        .write(&mut input)
+        .unwrap();
+    let window = zmodem::capabilities().subpacket_size * PER_ACK;
+    for offset in (window..len).step_by(window as usize).chain([len]) {
+        Header::new(Encoding::ZHEX, Frame::ZACK)
+            .with_count(offset)
+            .write(&mut input)
+            .unwrap();
+    }
+    Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+    Header::new(Encoding::ZHEX, Frame::ZFIN)
+        .write(&mut input)
+        .unwrap();
+    input
+}
@@ -0,0 +1,47 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Compares sending data which needs no escaping, and is written as is,
+//! with data where every chunk goes through the escaping. Run with
+//! `cargo bench --bench escape`.
+
+mod common;
+
+use common::{receiver, Replay};
+use std::io::{self, Cursor};
+use std::time::{Duration, Instant};
+
+const LEN: usize = 4 * 1024 * 1024;
+const ROUNDS: u32 = 10;
+
+/// Returns the fastest of `ROUNDS` transfers of `data`.
+fn bench(input: &[u8], data: &[u8]) -> Duration {
+    (0..ROUNDS)
+        .map(|_| {
+            let mut port = Replay(input, io::sink());
+            let mut file = Cursor::new(data);
+            let start = Instant::now();
+            let stats =
+                zmodem::write(&mut port, &mut file, "bench", Some(data.len() as u32)).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(stats.data_bytes, data.len() as u64);
+            elapsed
+        })
+        .min()
+        .unwrap()
+}
+
+fn main() {
+    let text = b"hello world\n".repeat(LEN / 12);
+    let input = receiver(text.len() as u32);
+    // A ZDLE in every chunk, for both subpacket sizes, sends all of them
+    // through the escaping while adding little to the output:
+    let mut escaped = text.clone();
+    for i in (0..escaped.len()).step_by(256) {
+        escaped[i] = 0x18;
+    }
+
+    for (name, data) in [("as is", &text), ("escaped", &escaped)] {
+        let elapsed = bench(&input, data);
+        let rate = data.len() as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0);
+        println!("{name:>8}: {elapsed:>10.2?} ({rate:.0} MiB/s)");
+    }
+}
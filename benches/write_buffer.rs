@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Compares receiving into a file with and without `Config::write_buffer`,
+//! by the writes which reach the file and by time. Run with
+//! `cargo bench --bench write_buffer`.
+
+mod common;
+
+use common::{receiver, Replay};
+use std::io::{self, Cursor, Write};
+use std::time::{Duration, Instant};
+use zmodem::Config;
+
+const LEN: usize = 4 * 1024 * 1024;
+const ROUNDS: u32 = 10;
+
+/// Counts the writes to a file.
+struct Counting(std::fs::File, usize);
+
+impl Write for Counting {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.1 += 1;
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Returns what the sender sends for `data`.
+fn sender(data: &[u8]) -> Vec<u8> {
+    let input = receiver(data.len() as u32);
+    let mut port = Replay(&input, vec![]);
+    let size = Some(data.len() as u32);
+    zmodem::write(&mut port, &mut Cursor::new(data), "bench", size).unwrap();
+    port.1
+}
+
+/// Returns the writes and the fastest of `ROUNDS` transfers of `len` bytes
+/// from `input`.
+fn bench(input: &[u8], len: usize, write_buffer: usize) -> (usize, Duration) {
+    let path = std::env::temp_dir().join(format!("zmodem-bench-{}", std::process::id()));
+    let config = Config {
+        write_buffer,
+        ..Config::default()
+    };
+    let mut writes = 0;
+    let elapsed = (0..ROUNDS)
+        .map(|_| {
+            let mut port = Replay(input, io::sink());
+            let mut out = Counting(std::fs::File::create(&path).unwrap(), 0);
+            let start = Instant::now();
+            zmodem::read_with(&mut port, &mut (None, 0), &mut out, &config).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(out.0.metadata().unwrap().len(), len as u64);
+            writes = out.1;
+            elapsed
+        })
+        .min()
+        .unwrap();
+    let _ = std::fs::remove_file(&path);
+    (writes, elapsed)
+}
+
+fn main() {
+    let data = b"hello world\n".repeat(LEN / 12);
+    let input = sender(&data);
+
+    for write_buffer in [0, 16 * 1024, 256 * 1024] {
+        let (writes, elapsed) = bench(&input, data.len(), write_buffer);
+        println!("{write_buffer:>8}: {writes:>6} writes, {elapsed:>10.2?}");
+    }
+}
@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Delays between retries
+
+use std::time::Duration;
+
+/// Gives the time to wait before each retry: before a request is repeated
+/// after a timeout, before a garbled header is answered with `ZNAK`, and
+/// before the receiver asks for garbled data again with `ZRPOS`. `attempt`
+/// is 0 for the first retry, and goes back to 0 once a header gets through.
+pub trait Backoff {
+    fn next_delay(&mut self, attempt: u32) -> Duration;
+}
+
+/// Waits the same time before each retry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fixed(pub Duration);
+
+impl Backoff for Fixed {
+    fn next_delay(&mut self, _attempt: u32) -> Duration {
+        self.0
+    }
+}
+
+/// Doubles the delay with each retry, starting from `initial`, up to `max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Exponential {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Exponential {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+}
+
+impl Backoff for Exponential {
+    fn next_delay(&mut self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+}
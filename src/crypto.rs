@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Encryption of the file data (`CANCRY`)
+
+/// Encrypts the data of a file in place as it is sent, and decrypts it as it
+/// is received. Each call gets the offset of `data` in the file, so that data
+/// sent again after an error is transformed in the same way as the first
+/// time.
+///
+/// Only the file data is encrypted: names, sizes and the rest of the
+/// protocol are sent in the clear. The CRCs of the subpackets cover the
+/// ciphertext, and so corruption on the line is found before decryption.
+pub trait Cipher {
+    fn encrypt(&mut self, offset: u32, data: &mut [u8]);
+    fn decrypt(&mut self, offset: u32, data: &mut [u8]);
+}
@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Ports made of separate streams
+
+use std::io::{self, Read, Write};
+
+/// A port which reads from one stream and writes to another, e.g. to the
+/// pipes of a child process:
+///
+/// ```no_run
+/// use std::process::{Command, Stdio};
+///
+/// let sz = Command::new("sz")
+///     .arg("file.bin")
+///     .stdin(Stdio::piped())
+///     .stdout(Stdio::piped())
+///     .spawn()
+///     .unwrap();
+/// let mut port = zmodem::Duplex::new(sz.stdout.unwrap(), sz.stdin.unwrap());
+/// let mut out = std::io::Cursor::new(Vec::new());
+/// zmodem::read(&mut port, &mut (None, 0), &mut out).unwrap();
+/// ```
+pub struct Duplex<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R, W> Duplex<R, W> {
+    pub fn new(reader: R, writer: W) -> Self {
+        Self { reader, writer }
+    }
+
+    pub fn into_inner(self) -> (R, W) {
+        (self.reader, self.writer)
+    }
+}
+
+impl<R, W> Read for Duplex<R, W>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl<R, W> Write for Duplex<R, W>
+where
+    W: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -7,33 +7,106 @@ use core::convert::TryFrom;
 use crc::{Crc, CRC_16_XMODEM, CRC_32_ISO_HDLC};
 use std::fmt::{self, Display};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 use tinyvec::{array_vec, ArrayVec};
 
+mod backoff;
+#[cfg(feature = "crypto")]
+mod crypto;
+mod duplex;
+#[cfg(feature = "lossy-link")]
+mod lossy;
+#[cfg(feature = "memmap2")]
+mod mmap;
+mod rate;
+mod sequential;
+mod tee;
+mod transcode;
+mod transcript;
+
+pub use backoff::{Backoff, Exponential, Fixed};
+#[cfg(feature = "crypto")]
+pub use crypto::Cipher;
+pub use duplex::Duplex;
+#[cfg(feature = "lossy-link")]
+pub use lossy::{LinkOptions, LossyLink};
+#[cfg(feature = "memmap2")]
+pub use mmap::MmapReader;
+pub use rate::RateLimitedPort;
+pub use sequential::{BufferedSequentialReader, ChannelReader};
+pub use tee::MultiWriter;
+pub use transcode::{CrlfToLf, LfToCrlf, Transcode, TranscodingWriter};
+pub use transcript::TranscriptPort;
+
 const CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
 const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
 const ZPAD: u8 = b'*';
 const ZDLE: u8 = 0x18;
 const XON: u8 = 0x11;
+/// `ZFILE` transport option (ZF2) of an encrypted file
+const ZTCRYPT: u8 = 2;
+/// `ZFILE` management option (ZF1) to transfer the file only if its CRC
+/// differs from the local copy
+const ZMCRC: u8 = 2;
+/// Maximum length of the `ZSINIT` attention sequence, including the null
+const ZATTNLEN: usize = 32;
+/// Attention sequence of a sender which has not set one with `ZSINIT`
+const DEFAULT_ATTENTION: [u8; 1] = [0xca];
+/// Aborts the session: `ZDLE` doubles as the ASCII cancel character, and the
+/// backspaces erase the cancels from a terminal which echoes them.
+const ABORT: [u8; 20] = [
+    ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, ZDLE, 0x08, 0x08, 0x08, 0x08, 0x08, 0x08,
+    0x08, 0x08, 0x08, 0x08,
+];
 
 const ZACK_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZACK);
-const ZDATA_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZDATA);
-const ZEOF_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZEOF);
 const ZFIN_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZFIN);
 const ZNAK_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZNAK);
 const ZRPOS_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZRPOS);
 const ZRQINIT_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZRQINIT);
+const ZSKIP_HEADER: Header = Header::new(Encoding::ZHEX, Frame::ZSKIP);
 
+#[cfg(not(feature = "small"))]
 const SUBPACKET_SIZE: u32 = 1024;
+/// Smaller subpackets trade throughput for stack and static memory.
+#[cfg(feature = "small")]
+const SUBPACKET_SIZE: u32 = 256;
 const SUBPACKET_PER_ACK: usize = 10;
+/// Scratch buffer size for escaping up to `SUBPACKET_SIZE` bytes at a time.
+const ESCAPED_SIZE: usize = worst_case_escaped_size(SUBPACKET_SIZE as usize);
 /// Buffer size with enough capacity for an escaped header.
 const HEADER_SIZE: usize = 32;
 
+#[cfg(not(feature = "small"))]
+const RX_BUFFER_SIZE: usize = 2048;
+#[cfg(feature = "small")]
+const RX_BUFFER_SIZE: usize = 512;
 /// Receive buffer
-type RxBuffer = ArrayVec<[u8; 2048]>;
+type RxBuffer = ArrayVec<[u8; RX_BUFFER_SIZE]>;
+/// Buffer length advertised in `ZRINIT`. Zero means that the receiver can
+/// take any subpacket size, which `small` cannot.
+#[cfg(not(feature = "small"))]
+const ZRINIT_BUFFER_LEN: u16 = 0;
+#[cfg(feature = "small")]
+const ZRINIT_BUFFER_LEN: u16 = SUBPACKET_SIZE as u16;
+
+#[cfg(not(feature = "small"))]
+const TX_BUFFER_SIZE: usize = 1024;
+#[cfg(feature = "small")]
+const TX_BUFFER_SIZE: usize = 512;
 /// Transmit buffer. The size is picked based on maximum subpacket size in the
 /// original 1988 ZMODEM specification.
-type TxBuffer = ArrayVec<[u8; 1024]>;
+type TxBuffer = ArrayVec<[u8; TX_BUFFER_SIZE]>;
+
+// A received subpacket is stored along with its ZCRC byte:
+const _: () = assert!(RX_BUFFER_SIZE > SUBPACKET_SIZE as usize);
+const _: () = assert!(ZRINIT_BUFFER_LEN as usize <= SUBPACKET_SIZE as usize);
+// ZFILE subpacket: a name of up to 255 bytes, NUL, decimal size and NUL:
+const _: () = assert!(TX_BUFFER_SIZE > 255 + 1 + 10);
 
 /// https://play.rust-lang.org/?version=stable&mode=debug&edition=2021&gist=20db24d9f0aaff4d13f0144416f34d46
 const ZDLE_TABLE: [u8; 0x100] = [
@@ -78,6 +151,100 @@ pub const UNZDLE_TABLE: [u8; 0x100] = [
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct InvalidData;
 
+/// Errors returned by `read` and `write`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Malformed data or a failed I/O operation
+    InvalidData,
+    /// The sender received its own `ZRQINIT`, i.e. the port echoes back
+    /// everything written to it
+    Loopback,
+    /// The transfer was canceled with `Config::cancel`, or aborted as per
+    /// `Config::abort_rejected_name`
+    Canceled,
+    /// The file was skipped as per `Config::on_exists` or
+    /// `Config::name_filter`
+    Skipped,
+    /// The sender encrypts the file without a cipher to decrypt it, or the
+    /// receiver cannot decrypt a file encrypted with `Config::cipher`
+    EncryptionUnsupported,
+    /// The name or the metadata of the file is too long
+    BadFilename,
+    /// The file to send cannot be opened, or is not a regular file
+    SourceUnavailable,
+    /// The receiver asked with `ZRPOS` for data past the end of the file
+    BadReposition,
+    /// The peer aborted the session with five `CAN` bytes in a row
+    Aborted,
+    /// `Config::subpackets_per_ack` is zero, or the data sent before an
+    /// acknowledgement would not fit the buffer length
+    BadWindow,
+    /// The sender announced more files than `Config::max_files`
+    TooManyFiles,
+}
+
+impl From<InvalidData> for Error {
+    fn from(_: InvalidData) -> Self {
+        Error::InvalidData
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidData => write!(f, "invalid data"),
+            Error::Loopback => write!(f, "port echoes back transmitted data"),
+            Error::Canceled => write!(f, "transfer canceled"),
+            Error::Skipped => write!(f, "file skipped"),
+            Error::EncryptionUnsupported => write!(f, "encryption not supported"),
+            Error::BadFilename => write!(f, "bad file name"),
+            Error::SourceUnavailable => write!(f, "source file unavailable"),
+            Error::BadReposition => write!(f, "reposition past end of file"),
+            Error::Aborted => write!(f, "session aborted by peer"),
+            Error::BadWindow => write!(f, "window does not fit the buffer"),
+            Error::TooManyFiles => write!(f, "too many files"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Error returned by `recv_file_with`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecvError {
+    pub error: Error,
+    /// Where the data received before the error has been kept, as per
+    /// `Config::keep_partial_on_error`
+    pub partial: Option<PathBuf>,
+}
+
+impl From<Error> for RecvError {
+    fn from(error: Error) -> Self {
+        Self {
+            error,
+            partial: None,
+        }
+    }
+}
+
+impl Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.partial {
+            Some(partial) => write!(
+                f,
+                "{}, partial file kept at {}",
+                self.error,
+                partial.display()
+            ),
+            None => self.error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+/// The input of a transfer. Anything `Read + Seek` is a `Reader`, which
+/// includes `&mut R` for such `R`, as std implements both for references.
 pub trait Reader {
     fn read(&mut self, buf: &mut [u8]) -> Result<u32, InvalidData>;
     fn seek(&mut self, offset: u32) -> Result<u32, InvalidData>;
@@ -98,8 +265,17 @@ where
     }
 }
 
+/// The output of a transfer. Anything `Write` is a `Writer`, which includes
+/// `&mut W` for such `W`.
 pub trait Writer {
     fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData>;
+
+    /// Writes as much of `buf` as there is room for, and returns the number
+    /// of bytes written. The receiver asks the sender to resend the rest
+    /// later, which slows it down to the pace of a full `Writer`.
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, InvalidData> {
+        self.write(buf).and(Ok(buf.len()))
+    }
 }
 
 impl<W> Writer for W
@@ -109,6 +285,259 @@ where
     fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData> {
         self.write_all(buf).or(Err(InvalidData))
     }
+
+    /// Stops at a write failing with `WouldBlock`.
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, InvalidData> {
+        let mut count = 0;
+        while count < buf.len() {
+            match Write::write(self, &buf[count..]) {
+                Ok(0) => return Err(InvalidData),
+                Ok(n) => count += n,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(_) => return Err(InvalidData),
+            }
+        }
+        Ok(count)
+    }
+}
+
+/// A `Writer` which can read back what has been written to it.
+pub trait ReadableWriter: Writer {
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<u32, InvalidData>;
+}
+
+/// A `Writer` which can move the position of the next write.
+pub trait SeekWriter: Writer {
+    fn seek(&mut self, offset: u32) -> Result<u32, InvalidData>;
+}
+
+impl SeekWriter for std::fs::File {
+    fn seek(&mut self, offset: u32) -> Result<u32, InvalidData> {
+        Ok(Seek::seek(self, SeekFrom::Start(offset as u64)).or(Err(InvalidData))? as u32)
+    }
+}
+
+impl SeekWriter for std::io::Cursor<Vec<u8>> {
+    fn seek(&mut self, offset: u32) -> Result<u32, InvalidData> {
+        self.set_position(offset as u64);
+        Ok(offset)
+    }
+}
+
+/// The output of a transfer, which may or may not support rewinding.
+trait Sink: Writer {
+    /// Moves back to `offset`, and returns false if it is not supported.
+    fn rewind(&mut self, offset: u32) -> Result<bool, InvalidData>;
+
+    /// Writes out anything held back.
+    fn flush(&mut self) -> Result<(), InvalidData> {
+        Ok(())
+    }
+
+    /// Called with each file announced by the sender, and returns false if
+    /// the file is to be skipped.
+    fn open(&mut self, _file: &File) -> Result<bool, InvalidData> {
+        Ok(true)
+    }
+
+    /// Called once all of `file` has been received, and returns true if the
+    /// session goes on with the next file of a batch.
+    fn close(&mut self, _file: &File) -> Result<bool, InvalidData> {
+        Ok(false)
+    }
+
+    /// Called when writing `file` has failed, and returns true if the
+    /// session can go on with the next file of a batch.
+    fn fail(&mut self, _file: &File) -> Result<bool, InvalidData> {
+        Ok(false)
+    }
+}
+
+struct Appending<'a, W>(&'a mut W);
+
+impl<W: Writer> Writer for Appending<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData> {
+        self.0.write(buf)
+    }
+
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, InvalidData> {
+        self.0.try_write(buf)
+    }
+}
+
+impl<W: Writer> Sink for Appending<'_, W> {
+    fn rewind(&mut self, _: u32) -> Result<bool, InvalidData> {
+        Ok(false)
+    }
+}
+
+struct Seeking<'a, W>(&'a mut W);
+
+impl<W: SeekWriter> Writer for Seeking<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData> {
+        self.0.write(buf)
+    }
+
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, InvalidData> {
+        self.0.try_write(buf)
+    }
+}
+
+impl<W: SeekWriter> Sink for Seeking<'_, W> {
+    fn rewind(&mut self, offset: u32) -> Result<bool, InvalidData> {
+        self.0.seek(offset).and(Ok(true))
+    }
+}
+
+/// Collects the data written to a `Sink` into writes of at least `len`
+/// bytes, as set by `Config::write_buffer`. Zero writes everything through.
+struct Coalescing<'a, S> {
+    sink: &'a mut S,
+    buf: Vec<u8>,
+    len: usize,
+}
+
+impl<'a, S: Sink> Coalescing<'a, S> {
+    fn new(sink: &'a mut S, len: usize) -> Self {
+        Self {
+            sink,
+            buf: Vec::with_capacity(len),
+            len,
+        }
+    }
+}
+
+impl<S: Sink> Writer for Coalescing<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData> {
+        if self.buf.is_empty() && buf.len() >= self.len {
+            return self.sink.write(buf);
+        }
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= self.len {
+            Sink::flush(self)?;
+        }
+        Ok(())
+    }
+
+    /// Stops taking data once the sink has no room for what is collected.
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, InvalidData> {
+        let mut count = 0;
+        loop {
+            if self.buf.is_empty() && buf.len() - count >= self.len {
+                return Ok(count + self.sink.try_write(&buf[count..])?);
+            }
+            let n = (buf.len() - count).min(self.len.saturating_sub(self.buf.len()));
+            self.buf.extend_from_slice(&buf[count..count + n]);
+            count += n;
+            if self.buf.len() < self.len {
+                return Ok(count);
+            }
+            let written = self.sink.try_write(&self.buf)?;
+            self.buf.drain(..written);
+            if !self.buf.is_empty() {
+                return Ok(count);
+            }
+        }
+    }
+}
+
+impl<S: Sink> Sink for Coalescing<'_, S> {
+    fn rewind(&mut self, offset: u32) -> Result<bool, InvalidData> {
+        Sink::flush(self)?;
+        self.sink.rewind(offset)
+    }
+
+    fn open(&mut self, file: &File) -> Result<bool, InvalidData> {
+        self.sink.open(file)
+    }
+
+    fn close(&mut self, file: &File) -> Result<bool, InvalidData> {
+        Sink::flush(self)?;
+        self.sink.close(file)
+    }
+
+    /// Drops what is collected, as it would only fail again.
+    fn fail(&mut self, file: &File) -> Result<bool, InvalidData> {
+        self.buf.clear();
+        self.sink.fail(file)
+    }
+
+    fn flush(&mut self) -> Result<(), InvalidData> {
+        if !self.buf.is_empty() {
+            self.sink.write(&self.buf)?;
+            self.buf.clear();
+        }
+        self.sink.flush()
+    }
+}
+
+/// What became of a file received with `read_batch`
+#[derive(Debug, PartialEq)]
+pub enum FileOutcome<W> {
+    /// All of the file has been written to the `Writer`
+    Received(W),
+    /// Writing the file has failed, and it has been skipped as per
+    /// `Config::continue_on_file_error`. The `Writer` has what was written
+    /// before.
+    Failed(W),
+}
+
+/// Receives each file of a batch into the `Writer` returned for it by
+/// `accept`, for `read_batch`.
+struct Batch<W, A> {
+    accept: A,
+    current: Option<W>,
+    files: Vec<(File, FileOutcome<W>)>,
+}
+
+impl<W: Writer, A> Writer for Batch<W, A> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData> {
+        self.current.as_mut().ok_or(InvalidData)?.write(buf)
+    }
+
+    fn try_write(&mut self, buf: &[u8]) -> Result<usize, InvalidData> {
+        self.current.as_mut().ok_or(InvalidData)?.try_write(buf)
+    }
+}
+
+impl<W, A> Sink for Batch<W, A>
+where
+    W: Writer,
+    A: FnMut(&File) -> Option<W>,
+{
+    fn rewind(&mut self, _: u32) -> Result<bool, InvalidData> {
+        Ok(false)
+    }
+
+    fn open(&mut self, file: &File) -> Result<bool, InvalidData> {
+        self.current = (self.accept)(file);
+        Ok(self.current.is_some())
+    }
+
+    fn close(&mut self, file: &File) -> Result<bool, InvalidData> {
+        if let Some(writer) = self.current.take() {
+            self.files
+                .push((file.clone(), FileOutcome::Received(writer)));
+        }
+        Ok(true)
+    }
+
+    fn fail(&mut self, file: &File) -> Result<bool, InvalidData> {
+        if let Some(writer) = self.current.take() {
+            self.files.push((file.clone(), FileOutcome::Failed(writer)));
+        }
+        Ok(true)
+    }
+}
+
+impl ReadableWriter for std::io::Cursor<Vec<u8>> {
+    fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<u32, InvalidData> {
+        let data = self.get_ref().get(offset as usize..).unwrap_or(&[]);
+        let count = buf.len().min(data.len());
+        buf[..count].copy_from_slice(&data[..count]);
+        Ok(count as u32)
+    }
 }
 
 #[repr(C)]
@@ -140,6 +569,40 @@ impl Header {
         u32::from_le_bytes(self.flags)
     }
 
+    /// Returns true for `ZDATA`, i.e. file data subpackets follow.
+    pub const fn is_data_frame(&self) -> bool {
+        matches!(self.kind, Frame::ZDATA)
+    }
+
+    /// Returns true if the frame is not followed by a data subpacket.
+    pub const fn is_control(&self) -> bool {
+        !matches!(
+            self.kind,
+            Frame::ZSINIT | Frame::ZFILE | Frame::ZDATA | Frame::ZCOMMAND | Frame::ZSTDERR
+        )
+    }
+
+    /// Returns true if the frame ends the session.
+    pub const fn is_terminal(&self) -> bool {
+        matches!(self.kind, Frame::ZFIN | Frame::ZCAN)
+    }
+
+    /// Returns true if the flags hold a 32-bit value, as returned by
+    /// `count()`, instead of flag bytes.
+    pub const fn carries_count(&self) -> bool {
+        matches!(
+            self.kind,
+            Frame::ZACK
+                | Frame::ZRPOS
+                | Frame::ZDATA
+                | Frame::ZEOF
+                | Frame::ZCRC
+                | Frame::ZCHALLENGE
+                | Frame::ZCOMPL
+                | Frame::ZFREECNT
+        )
+    }
+
     pub fn write_zrinit<P>(
         port: &mut P,
         encoding: Encoding,
@@ -149,14 +612,63 @@ impl Header {
     where
         P: Write,
     {
+        Self::zrinit(encoding, zrinit, count).write(port)
+    }
+
+    fn zrinit(encoding: Encoding, zrinit: Zrinit, count: u16) -> Self {
         let count = count.to_le_bytes();
         Self {
             encoding,
             kind: Frame::ZRINIT,
             flags: [count[0], count[1], 0, zrinit.bits()],
         }
-        .write(port)
-        .or(Err(InvalidData))
+    }
+
+    /// Writes `ZCOMPL`, which completes a request such as `ZCOMMAND` with
+    /// `status`, e.g. the exit status of the command.
+    pub fn write_zcompl<P>(
+        port: &mut P,
+        encoding: Encoding,
+        status: u32,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        Self::new(encoding, Frame::ZCOMPL)
+            .with_count(status)
+            .write(port)
+    }
+
+    fn write_zsinit<P>(
+        port: &mut P,
+        encoding: Encoding,
+        zsinit: &ZsinitParams,
+        dialect: Dialect,
+        escctl: bool,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        if zsinit.attention.len() >= ZATTNLEN {
+            return Err(InvalidData);
+        }
+        let mut tx_buf = TxBuffer::new();
+        tx_buf.extend_from_slice(&zsinit.attention);
+        tx_buf.push(b'\0');
+
+        Self::new(encoding, Frame::ZSINIT)
+            .with_flags(&[0, 0, 0, zsinit.flags.bits()])
+            .write_escctl(port, escctl, dialect)?;
+        write_subpacket_with(
+            port,
+            encoding,
+            Packet::ZCRCW,
+            &tx_buf,
+            &mut [0; ESCAPED_SIZE],
+            dialect,
+            escctl,
+        )?;
+        Ok(())
     }
 
     pub fn write_zfile<P>(
@@ -167,6 +679,35 @@ impl Header {
     where
         P: Write,
     {
+        Self::write_zfile_with(
+            port,
+            Encoding::ZBIN32,
+            name,
+            size,
+            None,
+            &Config::default(),
+            false,
+        )
+    }
+
+    /// Writes `ZFILE` like `write_zfile`, with the options in `config`. With
+    /// `crc`, `ZMCRC` is set and the CRC follows the metadata. With `escctl`,
+    /// all control characters are escaped.
+    fn write_zfile_with<P>(
+        port: &mut P,
+        encoding: Encoding,
+        name: &str,
+        size: u32,
+        crc: Option<u32>,
+        config: &Config,
+        escctl: bool,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        if name.len() > 255 {
+            return Err(InvalidData);
+        }
         let mut tx_buf = TxBuffer::new();
 
         tx_buf.truncate(0);
@@ -175,78 +716,175 @@ impl Header {
         // FIXME: Uses heap:
         tx_buf.extend_from_slice(size.to_string().as_bytes());
         tx_buf.push(b'\0');
+        if let Some(crc) = crc {
+            tx_buf.extend_from_slice(format!("{:08x}", crc).as_bytes());
+            tx_buf.push(b'\0');
+        }
 
+        // Announces the encryption in ZF2:
+        let crypt = if config.encrypts() { ZTCRYPT } else { 0 };
+        let manage = if crc.is_some() { ZMCRC } else { 0 };
         Self {
-            encoding: Encoding::ZBIN32,
+            encoding,
             kind: Frame::ZFILE,
-            flags: [0; 4],
+            flags: [0, crypt, manage, 0],
         }
-        .write(port)?;
+        .write_with(port, config.double_pad, escctl, config.dialect)?;
+
+        write_subpacket_with(
+            port,
+            encoding,
+            config.zfile_packet,
+            &tx_buf,
+            &mut [0; ESCAPED_SIZE],
+            config.dialect,
+            escctl,
+        )?;
+        Ok(())
+    }
 
-        write_subpacket(port, Encoding::ZBIN32, Packet::ZCRCW, &tx_buf)
+    pub fn read_zfile<P>(&self, port: &mut P) -> core::result::Result<Option<File>, Error>
+    where
+        P: Read + Write,
+    {
+        let file = self.read_file_info(port, Dialect::default())?;
+        if file.is_some() {
+            ZRPOS_HEADER.with_count(0).write(port)?;
+        }
+        Ok(file)
     }
 
-    pub fn read_zfile<P>(&self, port: &mut P) -> core::result::Result<Option<File>, InvalidData>
+    /// Reads the subpacket following `ZFILE`, without answering it. Reading
+    /// stops as soon as the name or the metadata is too long.
+    fn read_file_info<P>(
+        &self,
+        port: &mut P,
+        dialect: Dialect,
+    ) -> core::result::Result<Option<File>, Error>
     where
         P: Read + Write,
     {
         let mut rx_buf = RxBuffer::new();
-        let result = read_subpacket(port, self.encoding(), &mut rx_buf);
+        let result = read_subpacket_with(
+            port,
+            self.encoding(),
+            &mut rx_buf,
+            dialect,
+            zfile_fields_bounded,
+        );
         match result {
             Ok(_) => {
-                ZRPOS_HEADER.with_count(0).write(port)?;
-                let reader: ZfileReader = Cursor::new(rx_buf).read_ne().or(Err(InvalidData))?;
-                if reader.file_name.len() > 255 {
-                    return Err(InvalidData);
+                let mut info = FileInfo::try_from(&rx_buf[..])?;
+                if self.flags[2] != ZMCRC {
+                    info.crc32 = None;
                 }
-                let mut name = [0; 256];
-                for (i, b) in reader.file_name.as_slice().iter().enumerate() {
-                    name[i] = *b;
-                }
-                Ok(Some(File { name }))
+                Ok(Some(File { info }))
             }
-            _ => ZNAK_HEADER.write(port).and(Ok(None)),
+            Err(_) if !zfile_fields_bounded(&rx_buf) => Err(Error::BadFilename),
+            _ => ZNAK_HEADER
+                .write_dialect(port, dialect)
+                .and(Ok(None))
+                .map_err(Error::from),
         }
     }
 
     pub fn write<P>(&self, port: &mut P) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        self.write_padded(port, false)
+    }
+
+    /// Writes the header like `write`, in `dialect`.
+    fn write_dialect<P>(
+        &self,
+        port: &mut P,
+        dialect: Dialect,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        self.write_with(port, false, false, dialect)
+    }
+
+    /// Writes the header like `write`, but prefixes also a binary header
+    /// with two `ZPAD` bytes if `double_pad` is set.
+    pub fn write_padded<P>(
+        &self,
+        port: &mut P,
+        double_pad: bool,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        self.write_with(port, double_pad, false, Dialect::default())
+    }
+
+    /// Writes the header like `write_dialect`, but escapes the control
+    /// characters if `escctl` is set, for a peer which asked for it in
+    /// `ZSINIT` or `ZRINIT`.
+    fn write_escctl<P>(
+        &self,
+        port: &mut P,
+        escctl: bool,
+        dialect: Dialect,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        self.write_with(port, false, escctl, dialect)
+    }
+
+    fn write_with<P>(
+        &self,
+        port: &mut P,
+        double_pad: bool,
+        escctl: bool,
+        dialect: Dialect,
+    ) -> core::result::Result<(), InvalidData>
     where
         P: Write,
     {
         let mut out = array_vec!([u8; HEADER_SIZE]);
-        out.push(ZPAD);
-        if self.encoding == Encoding::ZHEX {
-            out.push(ZPAD);
+        out.push(dialect.zpad);
+        if double_pad || self.encoding == Encoding::ZHEX {
+            out.push(dialect.zpad);
         }
-        out.push(ZDLE);
+        out.push(dialect.zdle);
         out.push(self.encoding as u8);
+        // Skips ZPAD and encoding:
+        let start = out.len();
         out.push(self.kind as u8);
         out.extend_from_slice(&self.flags);
-        // Skips ZPAD and encoding:
-        let data = if self.encoding == Encoding::ZHEX {
-            &out[4..]
-        } else {
-            &out[3..]
-        };
         let mut crc = [0u8; 4];
-        let crc_len = make_crc(data, &mut crc, self.encoding);
+        let crc_len = make_crc(&out[start..], &mut crc, self.encoding);
         out.extend_from_slice(&crc[..crc_len]);
-        // Skips ZPAD and encoding:
         if self.encoding == Encoding::ZHEX {
-            let hex = hex::encode(&out[4..]);
-            out.truncate(4);
+            let hex = hex::encode(&out[start..]);
+            out.truncate(start);
             out.extend_from_slice(hex.as_bytes());
         }
         let mut escaped = [0u8; HEADER_SIZE];
-        // Does not corrupt `ZHEX` as the encoding byte is not escaped:
-        let escaped_len = escape_mem(&out[3..], &mut escaped[0..HEADER_SIZE]);
-        out.truncate(3);
+        let escaped_len = escape_mem(
+            &out[start..],
+            &mut escaped[0..HEADER_SIZE],
+            dialect.zdle,
+            escctl,
+        );
+        out.truncate(start);
         out.extend_from_slice(&escaped[..escaped_len]);
         if self.encoding == Encoding::ZHEX {
             // Add trailing CRLF for ZHEX transfer:
-            out.extend_from_slice(b"\r\n");
+            let mut trailer = array_vec!([u8; 3] => b'\r', b'\n');
             if self.kind != Frame::ZACK && self.kind != Frame::ZFIN {
-                out.push(XON);
+                trailer.push(XON);
+            }
+            for byte in trailer {
+                if escctl {
+                    out.extend_from_slice(&[dialect.zdle, byte ^ 0x40]);
+                } else {
+                    out.push(byte);
+                }
             }
         }
         port.write_all(&out).or(Err(InvalidData))
@@ -256,10 +894,32 @@ impl Header {
     where
         P: Read,
     {
-        let encoding = Encoding::try_from(read_byte(port)?)?;
+        Self::read_with(port, &Config::default())?.ok_or(InvalidData)
+    }
+
+    /// Reads a header, or returns `None` if it is in an unknown encoding and
+    /// `Config::skip_unknown_encoding` is set.
+    fn read_with<P>(
+        port: &mut P,
+        config: &Config,
+    ) -> core::result::Result<Option<Header>, InvalidData>
+    where
+        P: Read,
+    {
+        let byte = read_byte(port)?;
+        let encoding = match Encoding::try_from(byte) {
+            Ok(encoding) => encoding,
+            Err(_) => {
+                log::warn!("Header in unknown encoding {:#04x}", byte);
+                if config.skip_unknown_encoding {
+                    return Ok(None);
+                }
+                return Err(InvalidData);
+            }
+        };
         let mut out = array_vec!([u8; HEADER_SIZE]);
         for _ in 0..Header::unescaped_size(encoding) - 1 {
-            out.push(read_byte_unescaped(port)?);
+            out.push(read_byte_unescaped(port, config.dialect)?);
         }
         if encoding == Encoding::ZHEX {
             hex::decode_in_slice(&mut out).or(Err(InvalidData))?;
@@ -267,17 +927,49 @@ impl Header {
         }
         check_crc(&out[..5], &out[5..], encoding)?;
         let kind = Frame::try_from(out[0])?;
+        if encoding == Encoding::ZHEX {
+            Self::read_hex_trailer(port, kind, config.dialect)?;
+        }
         let mut header = Header::new(encoding, kind);
         header.flags.copy_from_slice(&out[1..=4]);
-        Ok(header)
+        Ok(Some(header))
     }
 
-    pub const fn with_count(&self, count: u32) -> Self {
-        Header {
-            encoding: self.encoding,
-            kind: self.kind,
-            flags: count.to_le_bytes(),
-        }
+    /// Consumes the CRLF after a `ZHEX` header, and the `XON` which follows
+    /// all but `ZACK` and `ZFIN`, so that the next frame starts right after.
+    /// The parity bit is ignored, as e.g. lrzsz sends the LF with it set,
+    /// and so are escapes, as sent to a peer which asked for `ESCCTL`.
+    fn read_hex_trailer<P>(
+        port: &mut P,
+        kind: Frame,
+        dialect: Dialect,
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Read,
+    {
+        let read = |port: &mut P| match read_byte(port)? {
+            byte if byte == dialect.zdle => Ok(UNZDLE_TABLE[read_byte(port)? as usize] & 0x7f),
+            byte => Ok(byte & 0x7f),
+        };
+        let mut byte = read(port)?;
+        if byte == b'\r' {
+            byte = read(port)?;
+        }
+        if byte != b'\n' {
+            return Err(InvalidData);
+        }
+        if kind != Frame::ZACK && kind != Frame::ZFIN && read(port)? != XON {
+            log::debug!("No XON after the ZHEX header");
+        }
+        Ok(())
+    }
+
+    pub const fn with_count(&self, count: u32) -> Self {
+        Header {
+            encoding: self.encoding,
+            kind: self.kind,
+            flags: count.to_le_bytes(),
+        }
     }
 
     pub const fn with_flags(&self, flags: &[u8; 4]) -> Self {
@@ -290,11 +982,12 @@ impl Header {
 
     const fn unescaped_size(encoding: Encoding) -> usize {
         match encoding {
-            Encoding::ZBIN => core::mem::size_of::<Header>() + 2,
-            Encoding::ZBIN32 => core::mem::size_of::<Header>() + 4,
+            Encoding::ZBIN | Encoding::ZBIN32 => {
+                core::mem::size_of::<Header>() + encoding.crc_len()
+            }
             // Encoding is stored as a single byte also for ZHEX, thus the
             // subtraction:
-            Encoding::ZHEX => (core::mem::size_of::<Header>() + 2) * 2 - 1,
+            Encoding::ZHEX => (core::mem::size_of::<Header>() + encoding.crc_len()) * 2 - 1,
         }
     }
 }
@@ -317,6 +1010,16 @@ pub enum Encoding {
 
 const ENCODINGS: &[Encoding] = &[Encoding::ZBIN, Encoding::ZHEX, Encoding::ZBIN32];
 
+impl Encoding {
+    /// Returns the length of the CRC in bytes.
+    pub const fn crc_len(&self) -> usize {
+        match self {
+            Encoding::ZBIN | Encoding::ZHEX => 2,
+            Encoding::ZBIN32 => 4,
+        }
+    }
+}
+
 impl TryFrom<u8> for Encoding {
     type Error = InvalidData;
 
@@ -423,6 +1126,7 @@ impl Display for Frame {
 
 bitflags! {
    /// `ZRINIT` flags
+   #[derive(Clone, Copy, Debug, PartialEq)]
    pub struct Zrinit: u8 {
         /// Can send and receive in full-duplex
         const CANFDX = 0x01;
@@ -436,6 +1140,19 @@ bitflags! {
         const CANLZW = 0x10;
         /// Can use 32-bit frame check
         const CANFC32 = 0x20;
+        /// Expects control character to be escaped
+        const ESCCTL = 0x40;
+        /// Expects 8th bit to be escaped. The byte after `ZDLE` keeps the
+        /// 8th bit, and `UNZDLE_TABLE` restores only 0x80..=0x9f and 0xff
+        /// from an escape, and so the sender of this crate cannot honor it.
+        const ESC8 = 0x80;
+    }
+}
+
+bitflags! {
+   /// `ZSINIT` flags
+   #[derive(Clone, Copy, Debug, PartialEq)]
+   pub struct Zsinit: u8 {
         /// Expects control character to be escaped
         const ESCCTL = 0x40;
         /// Expects 8th bit to be escaped
@@ -443,19 +1160,170 @@ bitflags! {
     }
 }
 
-#[derive(PartialEq)]
+/// Contents of `ZSINIT` sent by the sender before `ZFILE`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ZsinitParams {
+    pub flags: Zsinit,
+    /// Attention sequence, which the receiver sends to interrupt the sender.
+    /// At most 31 bytes.
+    pub attention: Vec<u8>,
+}
+
+/// Bytes which lead headers and escapes on the wire, for variants of ZMODEM
+/// which do not use the standard ones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dialect {
+    /// Leads each header, `*` in the standard
+    pub zpad: u8,
+    /// Leads each escape, and follows the `zpad` of a header. It needs to be
+    /// a control character other than CR, LF and XON, as escaping flips bit
+    /// 6 of a byte. The standard one is the ASCII cancel character.
+    pub zdle: u8,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self {
+            zpad: ZPAD,
+            zdle: ZDLE,
+        }
+    }
+}
+
+impl Dialect {
+    fn check(&self) -> core::result::Result<(), InvalidData> {
+        let zdle = self.zdle & 0x7f;
+        if zdle >= 0x20 || matches!(zdle, b'\r' | b'\n' | XON) || self.zpad == self.zdle {
+            log::error!("Unusable dialect {:?}", self);
+            return Err(InvalidData);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct File {
-    name: [u8; 256],
+    info: FileInfo,
+}
+
+impl File {
+    /// Returns the metadata announced by the sender in `ZFILE`.
+    pub fn info(&self) -> &FileInfo {
+        &self.info
+    }
+
+    /// Returns the name up to its NUL, or an empty name if it is not valid
+    /// UTF-8.
+    pub fn name(&self) -> &str {
+        &self.info.name
+    }
+
+    pub fn size(&self) -> Option<u32> {
+        self.info.size
+    }
+
+    pub fn mtime(&self) -> Option<u32> {
+        self.info.mtime
+    }
+
+    pub fn mode(&self) -> Option<u32> {
+        self.info.mode
+    }
+}
+
+/// Contents of the `ZFILE` subpacket: the file name, followed by optional
+/// metadata fields separated by spaces. Fields not sent by the sender are
+/// `None`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileInfo {
+    /// Name of the file, empty if it is not valid UTF-8
+    pub name: String,
+    /// Length of the file in bytes
+    pub size: Option<u32>,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: Option<u32>,
+    /// Unix file mode
+    pub mode: Option<u32>,
+    /// Number of files remaining in the batch, including this file
+    pub files_remaining: Option<u32>,
+    /// Number of bytes remaining in the batch, including this file
+    pub bytes_remaining: Option<u64>,
+    /// CRC-32 of the file, which follows the metadata with `ZMCRC`
+    pub crc32: Option<u32>,
+}
+
+impl TryFrom<&[u8]> for FileInfo {
+    type Error = InvalidData;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let mut cursor = Cursor::new(value);
+        let reader: ZfileReader = cursor.read_ne().or(Err(InvalidData))?;
+        if reader.file_name.len() > ZFILE_FIELD_LEN {
+            return Err(InvalidData);
+        }
+        let mut rest = value[cursor.position() as usize..].split(|b| *b == 0);
+        let metadata = rest.next().unwrap_or_default();
+        let metadata = core::str::from_utf8(metadata).or(Err(InvalidData))?;
+        // Length is decimal, whereas modification time, mode and serial
+        // number are octal:
+        let mut fields = metadata.split_ascii_whitespace();
+        let size = parse_field(fields.next(), u32::from_str_radix, 10)?;
+        let mtime = parse_field(fields.next(), u32::from_str_radix, 8)?;
+        let mode = parse_field(fields.next(), u32::from_str_radix, 8)?;
+        let _serial = parse_field(fields.next(), u32::from_str_radix, 8)?;
+        let files_remaining = parse_field(fields.next(), u32::from_str_radix, 10)?;
+        let bytes_remaining = parse_field(fields.next(), u64::from_str_radix, 10)?;
+        // Anything else after the metadata is ignored:
+        let crc32 = rest
+            .next()
+            .and_then(|field| core::str::from_utf8(field).ok())
+            .and_then(|field| u32::from_str_radix(field, 16).ok());
+        Ok(FileInfo {
+            // Rather than a mangled name, which could be created on disk:
+            name: String::from_utf8(reader.file_name.0).unwrap_or_default(),
+            size,
+            mtime,
+            mode,
+            files_remaining,
+            bytes_remaining,
+            crc32,
+        })
+    }
+}
+
+/// Longest name or metadata line accepted in `ZFILE`
+const ZFILE_FIELD_LEN: usize = 255;
+
+/// Returns true if the last field of a partly read `ZFILE` subpacket is no
+/// longer than `ZFILE_FIELD_LEN`.
+fn zfile_fields_bounded(data: &[u8]) -> bool {
+    data.iter()
+        .rev()
+        .take_while(|b| **b != 0)
+        .nth(ZFILE_FIELD_LEN)
+        .is_none()
+}
+
+fn parse_field<T>(
+    field: Option<&str>,
+    from_str_radix: fn(&str, u32) -> Result<T, core::num::ParseIntError>,
+    radix: u32,
+) -> Result<Option<T>, InvalidData> {
+    field
+        .map(|field| from_str_radix(field, radix))
+        .transpose()
+        .or(Err(InvalidData))
 }
 
 #[repr(u8)]
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 /// The ZMODEM subpacket type
 pub enum Packet {
     ZCRCE = 0x68,
     ZCRCG = 0x69,
     ZCRCQ = 0x6a,
+    #[default]
     ZCRCW = 0x6b,
 }
 
@@ -487,57 +1355,831 @@ struct ZfileReader {
 #[derive(PartialEq)]
 enum Stage {
     Waiting,
+    Initializing,
     Ready,
     Receiving,
+    /// Sending `Config::batch_sentinel`
+    Sentinel,
+    Finishing,
+}
+
+/// What to do when the file announced by the sender already exists
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExistsPolicy {
+    /// Receives the file from the start
+    #[default]
+    Overwrite,
+    /// Refuses the file with `ZSKIP`
+    Skip,
+    /// Receives only the data past the length of the existing file, which
+    /// the output is expected to append to. A file which is already
+    /// complete, i.e. at least as long as announced, is skipped.
+    Resume,
+    /// Receives the file under the first free name of the form `name (N)`,
+    /// with `N` inserted before the extension, e.g. `data (1).bin`
+    Rename,
+}
+
+/// Returns the length of the file, if it exists.
+pub type ExistsFn = dyn Fn(&FileInfo) -> Option<u32> + Send + Sync;
+
+/// Returns true if a file of the given name may be received.
+pub type NameFilterFn = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Receives the number of bytes transferred so far.
+pub type ProgressFn = dyn Fn(u32) + Send + Sync;
+
+/// Options for a transfer
+///
+/// `Config` is `Send` and `Sync`, and so callbacks stored in it need to be
+/// too, so that a transfer can run on a different thread from the one which
+/// set it up or cancels it.
+#[derive(Clone, Default)]
+pub struct Config {
+    /// Pads the last data subpacket to the full subpacket size, for hardware
+    /// which transfers only fixed-size blocks. The sender must announce the
+    /// file size, which the receiver uses to drop the padding, and so both
+    /// ends need to enable this.
+    pub fixed_subpacket_len: bool,
+    /// Prefixes also binary headers with two `ZPAD` bytes instead of one,
+    /// which some receivers find easier to synchronize on.
+    pub double_pad: bool,
+    /// Cancels the transfer when set, e.g. from another thread. The peer is
+    /// sent the abort sequence and `Error::Canceled` is returned.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Accepts `ZDATA` without a preceding `ZFILE`, as sent by some minimal
+    /// senders, and receives it as a file with an empty name.
+    pub accept_anonymous: bool,
+    /// Policy for files which `exists` reports to exist already
+    pub on_exists: ExistsPolicy,
+    /// Looks up the local state of the file announced by the sender. All
+    /// files are new without it.
+    pub exists: Option<Arc<ExistsFn>>,
+    /// Looks up the CRC-32 of the local copy of a file which `exists`
+    /// reports to exist. When the sender sends the CRC of the file with
+    /// `ZMCRC`, the file is skipped if both have the same length and CRC,
+    /// and received even with `ExistsPolicy::Skip` otherwise.
+    pub existing_crc: Option<Arc<ExistsFn>>,
+    /// Keeps the data received by `recv_file_with` before an error in
+    /// `<name>.part`, from where the transfer can be resumed later.
+    pub keep_partial_on_error: bool,
+    /// Buffer length advertised by the receiver in `ZRINIT`, after which the
+    /// sender waits for an acknowledgement. `None` or zero advertises the
+    /// default. A sender going past it is interrupted with its attention
+    /// sequence and asked to resend the rest.
+    pub buffer_len: Option<u16>,
+    /// Terminator of the `ZFILE` subpacket. The default `ZCRCW` waits for
+    /// the receiver, whereas e.g. `ZCRCQ` lets the sender pipeline.
+    pub zfile_packet: Packet,
+    /// Computes the CRC-32 of the data transferred, which is reported in
+    /// `TransferStats::crc32`.
+    pub compute_crc: bool,
+    /// Sends the CRC-32 of the file with `ZFILE` and sets `ZMCRC`, so that
+    /// the receiver can skip a local copy which is the same. This reads the
+    /// file twice, and so it needs to be seekable.
+    pub send_crc: bool,
+    /// Skips headers in an unknown encoding, e.g. from a newer or confused
+    /// peer, and waits for the next header instead of answering with
+    /// `ZNAK`.
+    pub skip_unknown_encoding: bool,
+    /// Sends `ZSINIT` with these parameters before `ZFILE`, and waits for the
+    /// receiver to acknowledge it.
+    pub send_zsinit: Option<ZsinitParams>,
+    /// Checks the name announced by the sender, e.g. against a pattern of
+    /// allowed names. A rejected file is refused with `ZSKIP`.
+    pub name_filter: Option<Arc<NameFilterFn>>,
+    /// Aborts the session on a name rejected by `name_filter` instead of
+    /// skipping the file.
+    pub abort_rejected_name: bool,
+    /// Called with the offset after each subpacket sent or received
+    pub progress: Option<Arc<ProgressFn>>,
+    /// Calls `progress` also when it has not been called for this long,
+    /// so that a stalled transfer is still reported. This needs the reads of
+    /// the port to return, e.g. after a read timeout.
+    pub heartbeat: Option<Duration>,
+    /// Time after which the other side is taken as gone if nothing has been
+    /// heard from it since the last byte or request, and the last request is
+    /// repeated. Reads of the port failing with `TimedOut` or `WouldBlock`
+    /// before then are retried, so that a port with a short read timeout
+    /// does not repeat requests during the idle gaps of a bursty channel.
+    /// `cancel` and `heartbeat` are not checked while retrying. Without it,
+    /// each read which times out counts.
+    pub timeout: Option<Duration>,
+    /// Number of reads of the port in a row which may return no data before
+    /// the port is taken as closed, for transports which do so when there is
+    /// nothing to read yet. The thread yields before each retry. Without
+    /// it, the first such read ends the input.
+    pub empty_read_retries: u32,
+    /// Restricts the sender to what the most minimal receivers understand.
+    /// It sends only `ZRQINIT`, `ZFILE`, `ZDATA`, `ZEOF` and `ZFIN`, plus
+    /// `ZCRC` if asked for it, and:
+    ///
+    /// - uses 16-bit CRCs even if the receiver offers `CANFC32`
+    /// - ends each subpacket with `ZCRCW` and waits for the `ZACK`
+    /// - ignores `send_zsinit`, `double_pad` and `zfile_packet`
+    /// - does not answer garbled headers with `ZNAK`
+    pub legacy_mode: bool,
+    /// Name of an empty file which the sender sends after the file, to mark
+    /// the end of the batch for receivers which expect one.
+    pub batch_sentinel: Option<String>,
+    /// Delays the retries after timeouts and garbled headers or data, so
+    /// that a struggling link is not flooded with requests. Without it, the
+    /// retries are immediate.
+    pub backoff: Option<Arc<Mutex<dyn Backoff + Send>>>,
+    /// Collects the data received into writes of at least this many bytes
+    /// instead of writing each subpacket, which saves system calls when
+    /// receiving into a file. The rest is written at `ZEOF`, before data is
+    /// written at another offset, and at the end of the session, also after
+    /// an error. Zero writes each subpacket as it arrives.
+    pub write_buffer: usize,
+    /// Bytes leading headers and escapes, for peers which do not use the
+    /// standard ones. Both ends need to use the same.
+    pub dialect: Dialect,
+    /// Length of the data subpackets sent, e.g. shorter ones for a noisy
+    /// link, where a garbled subpacket costs less to send again. The
+    /// receiver's buffer length can make them shorter still. `None` sends
+    /// the largest which fit the buffers, and sending fails with
+    /// `Error::InvalidData` on zero or a length which does not fit them.
+    pub subpacket_len: Option<u32>,
+    /// Data subpackets sent before asking for an acknowledgement, unless
+    /// the receiver's buffer length takes fewer. Together with the subpacket
+    /// length this is the window of data in flight, which has to fit in
+    /// `buffer_len` if both are set, and in the largest buffer length which
+    /// `ZRINIT` can advertise. `None` sends 10, and sending fails with
+    /// `Error::BadWindow` on zero or a window which does not fit.
+    pub subpackets_per_ack: Option<u32>,
+    /// Number of files which the receiver accepts in a session, against a
+    /// sender which would otherwise queue any number of them. The files
+    /// before are received in full, and the session is aborted with
+    /// `Error::TooManyFiles` when the sender announces one more.
+    pub max_files: Option<u32>,
+    /// Skips a file of a batch received with `read_batch` with `ZSKIP` when
+    /// writing it fails, or the sender overruns its end, and goes on with
+    /// the next file. `read_batch` reports it as `FileOutcome::Failed`.
+    /// Without it, the session fails.
+    pub continue_on_file_error: bool,
+    /// Encrypts the file data. The receiver advertises `CANCRY` and refuses
+    /// files which are not encrypted, and the sender fails with
+    /// `Error::EncryptionUnsupported` if the receiver does not advertise it.
+    #[cfg(feature = "crypto")]
+    pub cipher: Option<Arc<Mutex<dyn Cipher + Send>>>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = f.debug_struct("Config");
+        s.field("fixed_subpacket_len", &self.fixed_subpacket_len)
+            .field("double_pad", &self.double_pad)
+            .field("cancel", &self.cancel)
+            .field("accept_anonymous", &self.accept_anonymous)
+            .field("on_exists", &self.on_exists)
+            .field("exists", &self.exists.is_some())
+            .field("existing_crc", &self.existing_crc.is_some())
+            .field("keep_partial_on_error", &self.keep_partial_on_error)
+            .field("buffer_len", &self.buffer_len)
+            .field("zfile_packet", &self.zfile_packet)
+            .field("compute_crc", &self.compute_crc)
+            .field("send_crc", &self.send_crc)
+            .field("skip_unknown_encoding", &self.skip_unknown_encoding)
+            .field("send_zsinit", &self.send_zsinit)
+            .field("name_filter", &self.name_filter.is_some())
+            .field("abort_rejected_name", &self.abort_rejected_name)
+            .field("progress", &self.progress.is_some())
+            .field("heartbeat", &self.heartbeat)
+            .field("timeout", &self.timeout)
+            .field("empty_read_retries", &self.empty_read_retries)
+            .field("legacy_mode", &self.legacy_mode)
+            .field("batch_sentinel", &self.batch_sentinel)
+            .field("backoff", &self.backoff.is_some())
+            .field("write_buffer", &self.write_buffer)
+            .field("dialect", &self.dialect)
+            .field("subpacket_len", &self.subpacket_len)
+            .field("subpackets_per_ack", &self.subpackets_per_ack)
+            .field("max_files", &self.max_files)
+            .field("continue_on_file_error", &self.continue_on_file_error);
+        #[cfg(feature = "crypto")]
+        s.field("cipher", &self.cipher.is_some());
+        s.finish()
+    }
+}
+
+impl Config {
+    /// Sends the abort sequence if the transfer has been canceled.
+    fn check_cancel<P>(&self, port: &mut P) -> core::result::Result<(), Error>
+    where
+        P: Write,
+    {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::Relaxed) => {
+                send_cancel(port)?;
+                Err(Error::Canceled)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits as long as `backoff` asks for before retry number `attempt`,
+    /// and moves on to the next one.
+    fn back_off(&self, attempt: &mut u32) {
+        if let Some(backoff) = &self.backoff {
+            // A panic elsewhere leaves at worst an odd delay:
+            let delay = backoff
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .next_delay(*attempt);
+            std::thread::sleep(delay);
+        }
+        *attempt = attempt.saturating_add(1);
+    }
+
+    /// Length of the data subpackets sent, which has to fit `Buffers`
+    fn max_subpacket_len(&self) -> core::result::Result<u32, InvalidData> {
+        match self.subpacket_len {
+            None => Ok(SUBPACKET_SIZE),
+            Some(len @ 1..=SUBPACKET_SIZE) => Ok(len),
+            Some(len) => {
+                log::error!("Subpacket length {} out of 1..={}", len, SUBPACKET_SIZE);
+                Err(InvalidData)
+            }
+        }
+    }
+
+    /// Data subpackets sent before asking for an acknowledgement
+    fn subpackets_per_ack(&self) -> usize {
+        self.subpackets_per_ack
+            .map_or(SUBPACKET_PER_ACK, |count| count as usize)
+    }
+
+    /// Checks that the window of data sent before an acknowledgement fits
+    /// the buffers of both ends.
+    fn check_window(&self) -> core::result::Result<(), Error> {
+        let Some(count) = self.subpackets_per_ack else {
+            return Ok(());
+        };
+        let window = count as u64 * self.max_subpacket_len()? as u64;
+        let buffer_len = match self.buffer_len {
+            Some(len) if len != 0 => len,
+            _ => u16::MAX,
+        };
+        if count == 0 || window > buffer_len as u64 {
+            log::error!(
+                "Window of {} subpackets ({} bytes) does not fit a buffer of {} bytes",
+                count,
+                window,
+                buffer_len
+            );
+            return Err(Error::BadWindow);
+        }
+        Ok(())
+    }
+
+    /// Capabilities advertised by the receiver in `ZRINIT`. `CANCRY` is only
+    /// among them with a cipher.
+    fn zrinit_flags(&self) -> Zrinit {
+        let flags = Zrinit::CANOVIO | Zrinit::CANFC32;
+        if self.encrypts() {
+            flags | Zrinit::CANCRY
+        } else {
+            flags
+        }
+    }
+
+    #[cfg(feature = "crypto")]
+    fn encrypts(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    #[cfg(not(feature = "crypto"))]
+    fn encrypts(&self) -> bool {
+        false
+    }
+
+    #[cfg(feature = "crypto")]
+    fn encrypt(&self, offset: u32, data: &mut [u8]) -> core::result::Result<(), InvalidData> {
+        if let Some(cipher) = &self.cipher {
+            cipher.lock().or(Err(InvalidData))?.encrypt(offset, data);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "crypto")]
+    fn decrypt(&self, offset: u32, data: &mut [u8]) -> core::result::Result<(), InvalidData> {
+        if let Some(cipher) = &self.cipher {
+            cipher.lock().or(Err(InvalidData))?.decrypt(offset, data);
+        }
+        Ok(())
+    }
+
+    /// Returns the buffer length to advertise, which can only be smaller
+    /// than what the receive buffer can take.
+    fn zrinit_buffer_len(&self) -> u16 {
+        match self.buffer_len {
+            Some(len) if len != 0 && (ZRINIT_BUFFER_LEN == 0 || len < ZRINIT_BUFFER_LEN) => len,
+            _ => ZRINIT_BUFFER_LEN,
+        }
+    }
+
+    fn accepts_name(&self, name: &str) -> bool {
+        self.name_filter
+            .as_ref()
+            .map_or(true, |filter| filter(name))
+    }
+
+    /// Applies `on_exists` to a new file, and returns the offset to receive
+    /// it from, or `None` if it is to be skipped. The `received` bytes which
+    /// the caller already has are resumed from as with `ExistsPolicy::Resume`.
+    fn start_offset(&self, info: &mut FileInfo, received: u32) -> Option<u32> {
+        if received != 0 {
+            return match info.size {
+                Some(size) if received >= size => None,
+                _ => Some(received),
+            };
+        }
+        let exists = match &self.exists {
+            Some(exists) => exists,
+            None => return Some(0),
+        };
+        let len = match exists(info) {
+            Some(len) => len,
+            None => return Some(0),
+        };
+        if let (Some(crc), Some(existing_crc)) = (info.crc32, &self.existing_crc) {
+            if info.size.map_or(true, |size| size == len) && existing_crc(info) == Some(crc) {
+                return None;
+            }
+            if self.on_exists == ExistsPolicy::Skip {
+                return Some(0);
+            }
+        }
+        match self.on_exists {
+            ExistsPolicy::Overwrite => Some(0),
+            ExistsPolicy::Skip => None,
+            ExistsPolicy::Resume if info.size.is_some_and(|size| len >= size) => None,
+            ExistsPolicy::Resume => Some(len),
+            ExistsPolicy::Rename => {
+                let name = info.name.clone();
+                for n in 1.. {
+                    info.name = numbered(&name, n);
+                    if exists(info).is_none() {
+                        break;
+                    }
+                }
+                Some(0)
+            }
+        }
+    }
+}
+
+/// Inserts ` (n)` into `name` before the extension of its last component.
+fn numbered(name: &str, n: u32) -> String {
+    let start = name.rfind('/').map_or(0, |i| i + 1);
+    match name[start..].rfind('.') {
+        // A leading dot does not start an extension:
+        Some(i) if i > 0 => format!("{} ({}){}", &name[..start + i], n, &name[start + i..]),
+        _ => format!("{} ({})", name, n),
+    }
 }
 
 /// Sends a file using the ZMODEM file transfer protocol.
+///
+/// A read from `port` failing with `TimedOut` or `WouldBlock`, e.g. after a
+/// read timeout set on a serial port, makes the sender repeat its last
+/// request.
 pub fn write<P, F>(
     port: &mut P,
     file: &mut F,
     name: &str,
     size: Option<u32>,
-) -> core::result::Result<(), InvalidData>
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: Reader,
+{
+    write_with(port, file, name, size, &Config::default())
+}
+
+/// Sends a file like `write`, with the options in `config`.
+pub fn write_with<P, F>(
+    port: &mut P,
+    file: &mut F,
+    name: &str,
+    size: Option<u32>,
+    config: &Config,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: Reader,
+{
+    write_with_buffers(port, file, name, size, config, &mut Buffers::new())
+}
+
+/// Sends a file like `write_with`, using `buffers` instead of buffers on the
+/// stack. `buffers` can be reused from one transfer to the next.
+pub fn write_with_buffers<P, F>(
+    port: &mut P,
+    file: &mut F,
+    name: &str,
+    size: Option<u32>,
+    config: &Config,
+    buffers: &mut Buffers,
+) -> core::result::Result<TransferStats, Error>
 where
     P: Read + Write,
     F: Reader,
 {
+    write_files(port, core::iter::once((name, size, file)), config, buffers)
+}
+
+/// Sends `files` one after the other in one session, like `sz` with several
+/// files, with the options in `config`. A file which the receiver refuses
+/// with `ZSKIP` is not sent. The statistics are of the whole session, but
+/// `TransferStats::crc32` is of the last file.
+pub fn write_batch<'a, P, F, I>(
+    port: &mut P,
+    files: I,
+    config: &Config,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: Reader + 'a,
+    I: IntoIterator<Item = (&'a str, Option<u32>, &'a mut F)>,
+{
+    write_files(port, files.into_iter(), config, &mut Buffers::new())
+}
+
+/// A file of the batch being sent
+struct Outgoing<'a, F> {
+    name: &'a str,
+    size: Option<u32>,
+    file: &'a mut F,
+    /// Sent with `ZFILE` for `Config::send_crc`
+    crc: Option<u32>,
+}
+
+/// Takes the next file of the batch from `files`, or returns `None` at the
+/// end of the batch.
+fn next_outgoing<'a, F, I>(
+    files: &mut I,
+    tracker: &mut Tracker,
+) -> core::result::Result<Option<Outgoing<'a, F>>, InvalidData>
+where
+    F: Reader + 'a,
+    I: Iterator<Item = (&'a str, Option<u32>, &'a mut F)>,
+{
+    let Some((name, size, file)) = files.next() else {
+        return Ok(None);
+    };
+    let crc = if tracker.config.send_crc {
+        Some(file_crc(file, size.unwrap_or(0))?)
+    } else {
+        None
+    };
+    tracker.crc = RunningCrc::new(tracker.config);
+    Ok(Some(Outgoing {
+        name,
+        size,
+        file,
+        crc,
+    }))
+}
+
+/// Announces `outgoing` with `ZFILE`, or ends the batch with
+/// `Config::batch_sentinel` or `ZFIN`, and returns the next stage.
+fn announce<P, F>(
+    port: &mut P,
+    outgoing: &Option<Outgoing<F>>,
+    encoding: Encoding,
+    tracker: &Tracker,
+) -> core::result::Result<Stage, InvalidData>
+where
+    P: Write,
+{
+    let config = tracker.config;
+    match (outgoing, &config.batch_sentinel) {
+        (Some(outgoing), _) => {
+            let size = outgoing.size.unwrap_or(0);
+            Header::write_zfile_with(
+                port,
+                encoding,
+                outgoing.name,
+                size,
+                outgoing.crc,
+                config,
+                tracker.escctl,
+            )?;
+            Ok(Stage::Ready)
+        }
+        (None, Some(sentinel)) => {
+            Header::write_zfile_with(port, encoding, sentinel, 0, None, config, tracker.escctl)?;
+            Ok(Stage::Sentinel)
+        }
+        (None, None) => {
+            ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+            Ok(Stage::Finishing)
+        }
+    }
+}
+
+/// Sends the files of a batch, which has a single file for `write`.
+fn write_files<'a, P, F, I>(
+    port: &mut P,
+    mut files: I,
+    config: &Config,
+    buffers: &mut Buffers,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: Reader + 'a,
+    I: Iterator<Item = (&'a str, Option<u32>, &'a mut F)>,
+{
+    let legacy_mode = config.legacy_mode;
+    let config = Config {
+        double_pad: config.double_pad && !legacy_mode,
+        send_zsinit: config.send_zsinit.clone().filter(|_| !legacy_mode),
+        zfile_packet: if legacy_mode {
+            Packet::ZCRCW
+        } else {
+            config.zfile_packet
+        },
+        ..config.clone()
+    };
+    config.dialect.check()?;
+    let max_len = config.max_subpacket_len()?;
+    config.check_window()?;
+    let mut stats = TransferStats::default();
     let mut stage = Stage::Waiting;
+    let mut encoding = Encoding::ZBIN32;
+    let mut window = 0;
+    let mut at_eof = false;
+    // Offsets acknowledged by the receiver and sent to it:
+    let mut acked = 0;
+    let mut sent = 0;
+    let mut tracker = Tracker::new(&config);
+    let mut outgoing = None;
+    let port = &mut EofPort::new(port, &config);
+    // Retries since the last header received:
+    let mut attempt = 0;
 
-    ZRQINIT_HEADER.write(port)?;
+    ZRQINIT_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
     loop {
-        if read_zpad(port).is_err() {
-            continue;
+        config.check_cancel(port)?;
+        tracker.heartbeat();
+        if port.aborted {
+            log::error!("Receiver aborted the session");
+            return Err(Error::Aborted);
         }
-        let frame = match Header::read(port) {
-            Err(_) => {
-                ZNAK_HEADER.write(port)?;
+        if port.eof {
+            // All data has been sent if the receiver closes after ZEOF:
+            if !at_eof {
+                return Err(Error::InvalidData);
+            }
+            log::info!("Receiver closed the session without ZFIN");
+            stats.completion = Completion::PeerClosedEarly;
+            break;
+        }
+        let frame = if core::mem::take(&mut port.timed_out) {
+            // Nothing has been heard from the receiver, which is handled
+            // like a ZNAK:
+            config.back_off(&mut attempt);
+            ZNAK_HEADER
+        } else {
+            if read_zpad(port, config.dialect).is_err() {
                 continue;
             }
-            Ok(frame) => frame,
+            match Header::read_with(port, &config) {
+                Err(_) if port.eof || port.timed_out || port.aborted => continue,
+                Err(_) => {
+                    stats.crc_failures += 1;
+                    if !legacy_mode {
+                        config.back_off(&mut attempt);
+                        ZNAK_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                    }
+                    continue;
+                }
+                // Resynchronizes on the next header:
+                Ok(None) => continue,
+                Ok(Some(frame)) => {
+                    attempt = 0;
+                    frame
+                }
+            }
         };
         match frame.kind() {
             Frame::ZRINIT => match stage {
                 Stage::Waiting => {
-                    let size = size.unwrap_or(0);
-                    Header::write_zfile(port, name, size)?;
-                    stage = Stage::Ready;
+                    let zrinit = Zrinit::from_bits_truncate(frame.flags[3]);
+                    // Without CANCRY the receiver would write the ciphertext
+                    // as if it were the file:
+                    if config.encrypts() && !zrinit.contains(Zrinit::CANCRY) {
+                        port.write_all(&ABORT).or(Err(InvalidData))?;
+                        return Err(Error::EncryptionUnsupported);
+                    }
+                    // Without CANFC32 the receiver can only check 16-bit
+                    // CRCs:
+                    if !zrinit.contains(Zrinit::CANFC32) || legacy_mode {
+                        encoding = Encoding::ZBIN;
+                    }
+                    window = u16::from_le_bytes([frame.flags[0], frame.flags[1]]) as u32;
+                    // Without CANOVIO the receiver cannot write to disk while
+                    // receiving, and so each subpacket waits for an ACK:
+                    if !zrinit.contains(Zrinit::CANOVIO) || legacy_mode {
+                        window = match window {
+                            0 => max_len,
+                            _ => window.min(max_len),
+                        };
+                    }
+                    // A receiver behind a hostile terminal driver asks for all
+                    // control characters to be escaped:
+                    tracker.escctl = zrinit.contains(Zrinit::ESCCTL);
+                    stats.negotiated = Some(NegotiationSummary {
+                        encoding,
+                        window,
+                        escctl: tracker.escctl,
+                        esc8: zrinit.contains(Zrinit::ESC8),
+                        full_duplex: zrinit.contains(Zrinit::CANFDX),
+                    });
+                    outgoing = next_outgoing(&mut files, &mut tracker)?;
+                    at_eof = outgoing.is_none();
+                    if let Some(zsinit) = &config.send_zsinit {
+                        Header::write_zsinit(
+                            port,
+                            encoding,
+                            zsinit,
+                            config.dialect,
+                            tracker.escctl,
+                        )?;
+                        stage = Stage::Initializing;
+                    } else {
+                        stage = announce(port, &outgoing, encoding, &tracker)?;
+                    }
+                }
+                Stage::Initializing | Stage::Ready => (),
+                Stage::Receiving => {
+                    // The receiver has all of the file up to ZEOF:
+                    if at_eof {
+                        *stats.peer_received_bytes.get_or_insert(0) += sent as u64;
+                    }
+                    // ZRINIT after ZEOF asks for the next file, and so ZFIN is
+                    // only sent once there is none left:
+                    outgoing = next_outgoing(&mut files, &mut tracker)?;
+                    (acked, sent, at_eof) = (0, 0, outgoing.is_none());
+                    stage = announce(port, &outgoing, encoding, &tracker)?;
+                }
+                Stage::Sentinel | Stage::Finishing => {
+                    ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                    stage = Stage::Finishing;
+                }
+            },
+            Frame::ZRQINIT if stage == Stage::Waiting && frame == ZRQINIT_HEADER => {
+                return Err(Error::Loopback);
+            }
+            Frame::ZFIN if stage != Stage::Waiting => {
+                // The receiver may also close the session first:
+                if stage != Stage::Finishing {
+                    ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                    if !at_eof {
+                        stats.completion = Completion::PeerClosedEarly;
+                    }
+                }
+                port.write_all("OO".as_bytes()).or(Err(InvalidData))?;
+                break;
+            }
+            Frame::ZCRC if stage != Stage::Waiting => {
+                // The sentinel is empty:
+                let crc = match &mut outgoing {
+                    Some(outgoing) => file_crc(outgoing.file, frame.count())?,
+                    None => CRC32.checksum(&[]),
+                };
+                Header::new(Encoding::ZHEX, Frame::ZCRC)
+                    .with_count(crc)
+                    .write_escctl(port, tracker.escctl, config.dialect)?;
+            }
+            // The last request is repeated:
+            Frame::ZNAK => match stage {
+                Stage::Waiting => {
+                    ZRQINIT_HEADER.write_escctl(port, tracker.escctl, config.dialect)?
+                }
+                Stage::Initializing => {
+                    if let Some(zsinit) = &config.send_zsinit {
+                        Header::write_zsinit(
+                            port,
+                            encoding,
+                            zsinit,
+                            config.dialect,
+                            tracker.escctl,
+                        )?;
+                    }
+                }
+                Stage::Ready => {
+                    announce(port, &outgoing, encoding, &tracker)?;
+                }
+                Stage::Receiving => {
+                    if let Some(outgoing) = &mut outgoing {
+                        stats.retransmits += 1;
+                        let zrpos = ZRPOS_HEADER.with_count(acked);
+                        (sent, at_eof) = write_zdata(
+                            port,
+                            outgoing,
+                            &zrpos,
+                            encoding,
+                            window,
+                            buffers,
+                            &mut tracker,
+                        )?;
+                    }
+                }
+                Stage::Sentinel => {
+                    if let Some(sentinel) = &config.batch_sentinel {
+                        Header::write_zfile_with(
+                            port,
+                            encoding,
+                            sentinel,
+                            0,
+                            None,
+                            &config,
+                            tracker.escctl,
+                        )?;
+                    }
+                }
+                Stage::Finishing => {
+                    ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?
                 }
-                Stage::Ready => (),
-                Stage::Receiving => ZFIN_HEADER.write(port)?,
             },
+            Frame::ZACK if stage == Stage::Initializing => {
+                stage = announce(port, &outgoing, encoding, &tracker)?;
+            }
+            // The sentinel has no data:
+            Frame::ZRPOS | Frame::ZACK if stage == Stage::Sentinel => {
+                Header::new(encoding, Frame::ZEOF).write_with(
+                    port,
+                    config.double_pad,
+                    tracker.escctl,
+                    config.dialect,
+                )?;
+            }
+            Frame::ZSKIP if stage == Stage::Sentinel => {
+                ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                stage = Stage::Finishing;
+            }
+            // The receiver refuses the file, e.g. as it has it already:
+            Frame::ZSKIP if matches!(stage, Stage::Ready | Stage::Receiving) => {
+                if let Some(outgoing) = &outgoing {
+                    log::info!("Receiver skipped {:?}", outgoing.name);
+                }
+                outgoing = next_outgoing(&mut files, &mut tracker)?;
+                (acked, sent, at_eof) = (0, 0, outgoing.is_none());
+                stage = announce(port, &outgoing, encoding, &tracker)?;
+            }
             Frame::ZRPOS | Frame::ZACK => {
                 if stage == Stage::Waiting {
-                    ZRQINIT_HEADER.write(port)?;
-                } else {
-                    write_zdata(port, file, &frame)?;
+                    ZRQINIT_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                } else if let Some(outgoing) = &mut outgoing {
+                    // Would be answered with ZEOF at a bogus offset. Without
+                    // a size, this is only noticed when nothing can be read:
+                    if frame.kind() == Frame::ZRPOS
+                        && outgoing.size.is_some_and(|size| frame.count() > size)
+                    {
+                        log::error!("ZRPOS at {} past the end of the file", frame.count());
+                        port.write_all(&ABORT).or(Err(InvalidData))?;
+                        return Err(Error::BadReposition);
+                    }
+                    if stage == Stage::Receiving && frame.kind() == Frame::ZRPOS {
+                        stats.retransmits += 1;
+                    }
+                    // Unlike ZRPOS, ZACK only moves the window forward:
+                    if stage == Stage::Receiving && frame.kind() == Frame::ZACK {
+                        if frame.count() > sent {
+                            log::warn!("ZACK at {} past the data sent {}", frame.count(), sent);
+                            ZNAK_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                            continue;
+                        }
+                        if frame.count() < sent {
+                            // Stale, or for data before the end of the window:
+                            acked = acked.max(frame.count());
+                            continue;
+                        }
+                    }
+                    acked = frame.count();
+                    (sent, at_eof) = write_zdata(
+                        port,
+                        outgoing,
+                        &frame,
+                        encoding,
+                        window,
+                        buffers,
+                        &mut tracker,
+                    )?;
                     stage = Stage::Receiving;
                 }
             }
             _ => {
                 if stage == Stage::Waiting {
-                    ZRQINIT_HEADER.write(port)?;
+                    ZRQINIT_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
                 } else {
+                    stats.completion = match frame.kind() {
+                        Frame::ZCAN | Frame::ZABORT => Completion::CanceledRemote,
+                        _ => Completion::PeerClosedEarly,
+                    };
                     port.write_all("OO".as_bytes()).or(Err(InvalidData))?;
                     break;
                 }
@@ -545,193 +2187,1209 @@ where
         }
     }
 
-    Ok(())
+    stats.subpackets_sent = tracker.subpackets;
+    stats.data_bytes = tracker.data_bytes;
+    stats.wire_bytes = tracker.wire_bytes;
+    stats.crc32 = tracker.crc32();
+    Ok(stats)
 }
 
-/// Receives a file using the ZMODEM file transfer protocol.
-pub fn read<P, F>(
-    port: &mut P,
-    state: &mut (Option<File>, u32),
-    out: &mut F,
-) -> core::result::Result<(), InvalidData>
-where
-    P: Read + Write,
-    F: Writer,
-{
-    if state.0.is_none() {
-        assert_eq!(state.1, 0);
-        Header::write_zrinit(
-            port,
-            Encoding::ZHEX,
-            Zrinit::CANCRY | Zrinit::CANOVIO | Zrinit::CANFC32,
-            0,
-        )?
+/// How a session which did not fail ended
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Completion {
+    /// Both sides closed the session with `ZFIN`
+    #[default]
+    Clean,
+    /// The receiver went away after `ZEOF` without `ZFIN`, or ended the
+    /// session before the data was sent, e.g. with an unexpected header
+    PeerClosedEarly,
+    /// Canceled with `Config::cancel`. `read` and `write` fail with
+    /// `Error::Canceled` instead, and so this is only for applications
+    /// recording their own outcome.
+    CanceledLocal,
+    /// The receiver ended the session with `ZCAN` or `ZABORT`
+    CanceledRemote,
+}
+
+/// Statistics collected during a transfer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferStats {
+    /// Number of headers and subpackets which failed the CRC check or were
+    /// otherwise garbled
+    pub crc_failures: u64,
+    /// Number of times the sender was asked to resend from an earlier
+    /// position with `ZRPOS`
+    pub retransmits: u64,
+    /// CRC-32 of the data transferred, if `Config::compute_crc` is set. Of
+    /// the last file in a batch sent with `write_batch`.
+    pub crc32: Option<u32>,
+    /// Number of data subpackets sent, including those sent again
+    pub subpackets_sent: u64,
+    /// Number of data subpackets received which passed the CRC check,
+    /// including those received again
+    pub subpackets_received: u64,
+    /// What the sender settled on after the receiver's `ZRINIT`. Not set by
+    /// the receiver.
+    pub negotiated: Option<NegotiationSummary>,
+    /// Number of bytes of file data sent, including those sent again. Not
+    /// set by the receiver.
+    pub data_bytes: u64,
+    /// Number of bytes written for the data subpackets, after escaping and
+    /// including the CRCs. Not set by the receiver.
+    pub wire_bytes: u64,
+    /// Length of the data which the receiver confirmed having received, by
+    /// answering `ZEOF` with `ZRINIT`, summed over a batch. `None` if no
+    /// file was confirmed. Not set by the receiver.
+    pub peer_received_bytes: Option<u64>,
+    /// How the session ended
+    pub completion: Completion,
+}
+
+impl TransferStats {
+    /// Returns `wire_bytes` relative to `data_bytes`, or `None` if no data
+    /// was sent. Below 1.0 the data took less room on the wire than on
+    /// disk; escaping and CRCs otherwise keep it at or above 1.0.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.data_bytes > 0).then(|| self.wire_bytes as f64 / self.data_bytes as f64)
     }
+}
 
-    loop {
-        if read_zpad(port).is_err() {
-            continue;
+/// Parameters of a session as negotiated in the handshake, for diagnostics.
+/// Its `Display` gives a one-line summary, e.g.
+/// `CRC32, window=2048, ESCCTL=on, ESC8=off, FDX`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NegotiationSummary {
+    /// Encoding of the data
+    pub encoding: Encoding,
+    /// Bytes sent before waiting for an acknowledgement, or 0 if the sender
+    /// streams without waiting
+    pub window: u32,
+    /// Whether the receiver expects control characters to be escaped
+    pub escctl: bool,
+    /// Whether the receiver expects the 8th bit to be escaped
+    pub esc8: bool,
+    /// Whether the receiver can send and receive in full-duplex
+    pub full_duplex: bool,
+}
+
+impl Display for NegotiationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let on = |flag| if flag { "on" } else { "off" };
+        match self.encoding {
+            Encoding::ZBIN32 => write!(f, "CRC32")?,
+            Encoding::ZBIN | Encoding::ZHEX => write!(f, "CRC16")?,
         }
-        let frame = match Header::read(port) {
-            Err(_) => {
-                ZNAK_HEADER.write(port)?;
-                continue;
-            }
-            Ok(frame) => frame,
-        };
-        match frame.kind() {
+        match self.window {
+            0 => write!(f, ", window=none")?,
+            window => write!(f, ", window={}", window)?,
+        }
+        write!(
+            f,
+            ", ESCCTL={}, ESC8={}, {}",
+            on(self.escctl),
+            on(self.esc8),
+            if self.full_duplex { "FDX" } else { "HDX" }
+        )
+    }
+}
+
+/// Scratch buffers of the sender, for `write_with_buffers`. They take a few
+/// KiB, which can then be allocated once, e.g. on the heap, rather than on
+/// the stack each time data is sent.
+pub struct Buffers {
+    /// Data of the current subpacket and of the one read ahead
+    data: [[u8; SUBPACKET_SIZE as usize]; 2],
+    /// Escaped subpacket data
+    escaped: [u8; ESCAPED_SIZE],
+}
+
+impl Buffers {
+    pub fn new() -> Self {
+        Self {
+            data: [[0; SUBPACKET_SIZE as usize]; 2],
+            escaped: [0; ESCAPED_SIZE],
+        }
+    }
+}
+
+impl Default for Buffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// CRC-32 of the data of a transfer, which is computed as the data passes.
+/// Data sent again after a rewind is only counted once.
+struct RunningCrc {
+    digest: crc::Digest<'static, u32>,
+    /// Offset up to which the data has been counted, set by the first data
+    end: Option<u32>,
+}
+
+impl RunningCrc {
+    fn new(config: &Config) -> Option<Self> {
+        config.compute_crc.then(|| RunningCrc {
+            digest: CRC32.digest(),
+            end: None,
+        })
+    }
+
+    fn update(&mut self, offset: u32, data: &[u8]) {
+        let end = *self.end.get_or_insert(offset);
+        if offset > end {
+            return;
+        }
+        if let Some(data) = data.get((end - offset) as usize..) {
+            self.digest.update(data);
+            self.end = Some(end + data.len() as u32);
+        }
+    }
+
+    fn finalize(self) -> u32 {
+        self.digest.finalize()
+    }
+}
+
+/// Follows the data of a transfer as it passes, for `Config::compute_crc`
+/// and `Config::progress`.
+struct Tracker<'a> {
+    config: &'a Config,
+    crc: Option<RunningCrc>,
+    /// Offset after the data seen last
+    offset: u32,
+    /// Number of subpackets seen
+    subpackets: u64,
+    /// Whether the peer asked for escaped control characters in `ZSINIT`
+    escctl: bool,
+    /// Number of bytes of data seen
+    data_bytes: u64,
+    /// Number of bytes written for the subpackets
+    wire_bytes: u64,
+    /// Time of the last progress report
+    reported: Instant,
+}
+
+impl<'a> Tracker<'a> {
+    fn new(config: &'a Config) -> Self {
+        Self {
+            config,
+            crc: RunningCrc::new(config),
+            offset: 0,
+            subpackets: 0,
+            escctl: false,
+            data_bytes: 0,
+            wire_bytes: 0,
+            reported: Instant::now(),
+        }
+    }
+
+    fn update(&mut self, offset: u32, data: &[u8]) {
+        if let Some(crc) = &mut self.crc {
+            crc.update(offset, data);
+        }
+        self.offset = offset + data.len() as u32;
+        self.subpackets += 1;
+        self.data_bytes += data.len() as u64;
+        self.report();
+    }
+
+    fn report(&mut self) {
+        if let Some(progress) = &self.config.progress {
+            progress(self.offset);
+            self.reported = Instant::now();
+        }
+    }
+
+    /// Reports the progress again if nothing has been reported for
+    /// `Config::heartbeat`.
+    fn heartbeat(&mut self) {
+        if let Some(heartbeat) = self.config.heartbeat {
+            if self.reported.elapsed() >= heartbeat {
+                self.report();
+            }
+        }
+    }
+
+    fn crc32(self) -> Option<u32> {
+        self.crc.map(RunningCrc::finalize)
+    }
+}
+
+/// Receives a file using the ZMODEM file transfer protocol.
+///
+/// `state` holds the file announced by the sender and the number of bytes
+/// written to `out`. The count only includes data which `out` accepted, and
+/// it is kept up to date also when an error is returned, so that the caller
+/// knows where a failed transfer stopped.
+///
+/// A download which has failed can be resumed in a new session by starting
+/// with `(None, n)`, where `out` already holds the first `n` bytes of the
+/// file and writes after them: the sender is asked with `ZRPOS` to send the
+/// rest from `n`. A file which is no longer than `n` is skipped.
+///
+/// Garbled data is requested again from the sender, and the number of such
+/// failures is reported in the returned `TransferStats`. Receiving into
+/// `std::io::sink()` thus measures the error rate of a link. As in `write`,
+/// a read timeout makes the receiver repeat its last request. Commands sent
+/// with `ZCOMMAND` are not executed, but answered with `ZCOMPL` status 1.
+///
+/// Malformed input from the port results in an error, never in a panic.
+///
+/// If the sender restarts the session with `ZRQINIT` after data has been
+/// written, `InvalidData` is returned, as `out` cannot be rewound.
+pub fn read<P, F>(
+    port: &mut P,
+    state: &mut (Option<File>, u32),
+    out: &mut F,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: Writer,
+{
+    read_with(port, state, out, &Config::default())
+}
+
+/// Receives a file like `read`, with the options in `config`.
+pub fn read_with<P, F>(
+    port: &mut P,
+    state: &mut (Option<File>, u32),
+    out: &mut F,
+    config: &Config,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: Writer,
+{
+    receive(port, state, &mut Appending(out), config)
+}
+
+/// Receives a file like `read_with` into a seekable `out`. Data which the
+/// sender retransmits from an earlier offset overwrites the data already
+/// written, instead of being refused with `ZRPOS`. `state.1` is then the
+/// position in `out`.
+pub fn read_seekable_with<P, F>(
+    port: &mut P,
+    state: &mut (Option<File>, u32),
+    out: &mut F,
+    config: &Config,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    F: SeekWriter,
+{
+    receive(port, state, &mut Seeking(out), config)
+}
+
+/// Receives the files of a batch, like `rz`, until the sender ends the
+/// session. `accept` is called with each file announced by the sender, and
+/// returns the `Writer` to receive it into, or `None` to skip it with
+/// `ZSKIP`. It can be called again for the same file if the sender repeats
+/// `ZFILE`. Returns the files accepted, each with its `Writer` and whether
+/// it has been received, or has failed as per
+/// `Config::continue_on_file_error`.
+pub fn read_batch<P, W, A>(
+    port: &mut P,
+    accept: A,
+    config: &Config,
+) -> core::result::Result<Vec<(File, FileOutcome<W>)>, Error>
+where
+    P: Read + Write,
+    W: Writer,
+    A: FnMut(&File) -> Option<W>,
+{
+    let mut batch = Batch {
+        accept,
+        current: None,
+        files: Vec::new(),
+    };
+    match receive(port, &mut (None, 0), &mut batch, config) {
+        // Nothing has been received if all files are skipped:
+        Ok(_) | Err(Error::Skipped) => Ok(batch.files),
+        Err(err) => Err(err),
+    }
+}
+
+fn receive<P, S>(
+    port: &mut P,
+    state: &mut (Option<File>, u32),
+    out: &mut S,
+    config: &Config,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    S: Sink,
+{
+    let mut out = Coalescing::new(out, config.write_buffer);
+    let result = receive_coalesced(port, state, &mut out, config);
+    // The data received before an error is kept too, e.g. for
+    // `keep_partial_on_error`:
+    let flushed = out.flush();
+    let stats = result?;
+    flushed?;
+    Ok(stats)
+}
+
+fn receive_coalesced<P, S>(
+    port: &mut P,
+    state: &mut (Option<File>, u32),
+    out: &mut S,
+    config: &Config,
+) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+    S: Sink,
+{
+    config.dialect.check()?;
+    let mut stats = TransferStats::default();
+    let mut skipped = false;
+    // Whether a file of a batch has been received and closed:
+    let mut closed = false;
+    // Files accepted so far, for `Config::max_files`:
+    let mut files = 0;
+    let mut tracker = Tracker::new(config);
+    // Sent to interrupt the sender before asking it to resend:
+    let mut attention = DEFAULT_ATTENTION.to_vec();
+    let port = &mut EofPort::new(port, config);
+    // Retries since the last header or data received:
+    let mut attempt = 0;
+    let zrinit = config.zrinit_flags();
+    // Offset of the last ZRPOS sent after a failed ZDATA, until the sender
+    // gets there:
+    let mut repositioned = None;
+
+    if state.0.is_none() {
+        Header::zrinit(Encoding::ZHEX, zrinit, config.zrinit_buffer_len()).write_escctl(
+            port,
+            tracker.escctl,
+            config.dialect,
+        )?
+    }
+
+    loop {
+        config.check_cancel(port)?;
+        tracker.heartbeat();
+        if port.aborted {
+            log::error!("Sender aborted the session");
+            return Err(Error::Aborted);
+        }
+        if port.eof {
+            return Err(Error::InvalidData);
+        }
+        let frame = if core::mem::take(&mut port.timed_out) {
+            // Nothing has been heard from the sender, which is handled like
+            // a ZNAK:
+            config.back_off(&mut attempt);
+            ZNAK_HEADER
+        } else {
+            if read_zpad(port, config.dialect).is_err() {
+                continue;
+            }
+            match Header::read_with(port, config) {
+                Err(_) if port.eof || port.timed_out || port.aborted => continue,
+                // Likely a ZPAD in the data still in flight after a ZRPOS,
+                // which a ZNAK would only make the sender resend:
+                Err(_) if repositioned.is_some() => continue,
+                Err(_) => {
+                    stats.crc_failures += 1;
+                    config.back_off(&mut attempt);
+                    ZNAK_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                    continue;
+                }
+                // Resynchronizes on the next header:
+                Ok(None) => continue,
+                // ZDATA only counts once its data gets through too:
+                Ok(Some(frame)) => {
+                    if frame.kind() != Frame::ZDATA {
+                        attempt = 0;
+                    }
+                    frame
+                }
+            }
+        };
+        match frame.kind() {
             Frame::ZFILE => {
+                match (frame.flags[1] == ZTCRYPT, config.encrypts()) {
+                    (true, false) => {
+                        // Ciphertext would be written as if it were the file:
+                        port.write_all(&ABORT).or(Err(InvalidData))?;
+                        return Err(Error::EncryptionUnsupported);
+                    }
+                    (false, true) => {
+                        // And plaintext would be decrypted:
+                        log::error!("File not encrypted");
+                        port.write_all(&ABORT).or(Err(InvalidData))?;
+                        return Err(Error::InvalidData);
+                    }
+                    _ => (),
+                }
                 if state.0.is_none() || state.1 == 0 {
-                    assert_eq!(state.1, 0);
-                    state.0 = frame.read_zfile(port)?;
+                    state.0 = match frame.read_file_info(port, config.dialect) {
+                        Err(Error::BadFilename) => {
+                            port.write_all(&ABORT).or(Err(InvalidData))?;
+                            return Err(Error::BadFilename);
+                        }
+                        result => result?,
+                    };
+                    match &mut state.0 {
+                        // The file before has been closed at its ZEOF:
+                        Some(_) if config.max_files.is_some_and(|max| files >= max) => {
+                            log::error!("More files than the limit of {}", files);
+                            port.write_all(&ABORT).or(Err(InvalidData))?;
+                            *state = (None, 0);
+                            return Err(Error::TooManyFiles);
+                        }
+                        Some(file) if !config.accepts_name(&file.info.name) => {
+                            log::warn!("Rejected file name {:?}", file.info.name);
+                            if config.abort_rejected_name {
+                                port.write_all(&ABORT).or(Err(InvalidData))?;
+                                return Err(Error::Canceled);
+                            }
+                            skipped = true;
+                            *state = (None, 0);
+                            ZSKIP_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                        }
+                        Some(file) => match config.start_offset(&mut file.info, state.1) {
+                            Some(offset) if out.open(file)? => {
+                                files += 1;
+                                state.1 = offset;
+                                ZRPOS_HEADER.with_count(offset).write_escctl(
+                                    port,
+                                    tracker.escctl,
+                                    config.dialect,
+                                )?;
+                            }
+                            _ => {
+                                skipped = true;
+                                *state = (None, 0);
+                                ZSKIP_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                            }
+                        },
+                        None => stats.crc_failures += 1,
+                    }
                 }
             }
             Frame::ZDATA => {
+                if state.0.is_none() && config.accept_anonymous && !skipped {
+                    state.0 = Some(File {
+                        info: FileInfo::default(),
+                    });
+                }
                 if state.0.is_none() {
-                    Header::write_zrinit(
-                        port,
-                        Encoding::ZHEX,
-                        Zrinit::CANCRY | Zrinit::CANOVIO | Zrinit::CANFC32,
-                        0,
-                    )?;
-                } else if frame.count() != state.1 {
-                    ZRPOS_HEADER.with_count(state.1).write(port)?
+                    Header::zrinit(Encoding::ZHEX, zrinit, config.zrinit_buffer_len())
+                        .write_escctl(port, tracker.escctl, config.dialect)?;
+                } else if frame.count() != state.1
+                    && !(frame.count() < state.1 && out.rewind(frame.count())?)
+                {
+                    // Asking again for each burst still in flight would
+                    // make the sender resend it over and over:
+                    if repositioned != Some(state.1) {
+                        stats.retransmits += 1;
+                        ZRPOS_HEADER.with_count(state.1).write_escctl(
+                            port,
+                            tracker.escctl,
+                            config.dialect,
+                        )?
+                    }
                 } else {
-                    read_zdata(frame.encoding() as u8, &mut state.1, port, out)?;
+                    state.1 = frame.count();
+                    repositioned = None;
+                    let limit = match &state.0 {
+                        Some(file) if config.fixed_subpacket_len => file.info().size,
+                        _ => None,
+                    };
+                    let complete = match read_zdata(
+                        frame.encoding() as u8,
+                        &mut state.1,
+                        limit,
+                        port,
+                        out,
+                        &mut stats,
+                        &mut tracker,
+                    ) {
+                        Err(_) if skip_failed(port, state, out, &tracker)? => {
+                            skipped = true;
+                            repositioned = None;
+                            continue;
+                        }
+                        result => result?,
+                    };
+                    if complete {
+                        attempt = 0;
+                    } else {
+                        stats.retransmits += 1;
+                        config.back_off(&mut attempt);
+                        port.write_all(&attention).or(Err(InvalidData))?;
+                        ZRPOS_HEADER.with_count(state.1).write_escctl(
+                            port,
+                            tracker.escctl,
+                            config.dialect,
+                        )?;
+                        repositioned = Some(state.1);
+                    }
+                    // The data has been asked for again already:
+                    port.timed_out = false;
                 }
             }
             Frame::ZEOF if state.0.is_some() => {
-                if frame.count() != state.1 {
-                    log::error!(
-                        "ZEOF offset mismatch: frame({}) != recv({})",
-                        frame.count(),
-                        state.1
-                    );
+                let complete = check_zeof(frame.count(), state.1).and_then(|complete| {
+                    if complete {
+                        out.flush()?;
+                    }
+                    Ok(complete)
+                });
+                let complete = match complete {
+                    Err(_) if skip_failed(port, state, out, &tracker)? => {
+                        skipped = true;
+                        continue;
+                    }
+                    result => result?,
+                };
+                if complete {
+                    // A batch goes on with the next file:
+                    if state.0.as_ref().map_or(Ok(false), |file| out.close(file))? {
+                        *state = (None, 0);
+                        closed = true;
+                    }
+                    Header::zrinit(Encoding::ZHEX, zrinit, config.zrinit_buffer_len())
+                        .write_escctl(port, tracker.escctl, config.dialect)?
                 } else {
-                    Header::write_zrinit(
+                    stats.retransmits += 1;
+                    ZRPOS_HEADER.with_count(state.1).write_escctl(
                         port,
-                        Encoding::ZHEX,
-                        Zrinit::CANCRY | Zrinit::CANOVIO | Zrinit::CANFC32,
-                        0,
+                        tracker.escctl,
+                        config.dialect,
                     )?
                 }
             }
-            Frame::ZFIN if state.0.is_some() => {
-                ZFIN_HEADER.write(port)?;
+            Frame::ZFIN if state.0.is_some() || closed => {
+                ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
                 break;
             }
-            _ if state.0.is_none() => Header::write_zrinit(
-                port,
+            Frame::ZFIN if skipped => {
+                ZFIN_HEADER.write_escctl(port, tracker.escctl, config.dialect)?;
+                return Err(Error::Skipped);
+            }
+            Frame::ZSINIT => {
+                let mut rx_buf = RxBuffer::new();
+                match read_subpacket(port, frame.encoding(), &mut rx_buf, config.dialect) {
+                    Ok(_) => {
+                        log::debug!("ZSINIT flags {:#04x}", frame.flags[3]);
+                        tracker.escctl =
+                            Zsinit::from_bits_truncate(frame.flags[3]).contains(Zsinit::ESCCTL);
+                        attention = rx_buf
+                            .split(|b| *b == 0)
+                            .next()
+                            .unwrap_or_default()
+                            .to_vec();
+                        attention.truncate(ZATTNLEN - 1);
+                        ZACK_HEADER.with_count(1).write_escctl(
+                            port,
+                            tracker.escctl,
+                            config.dialect,
+                        )?
+                    }
+                    Err(_) => {
+                        stats.crc_failures += 1;
+                        ZNAK_HEADER.write_escctl(port, tracker.escctl, config.dialect)?
+                    }
+                }
+            }
+            Frame::ZCOMMAND => {
+                let mut rx_buf = RxBuffer::new();
+                match read_subpacket(port, frame.encoding(), &mut rx_buf, config.dialect) {
+                    Ok(_) => {
+                        // Commands from the peer are never executed:
+                        let command = String::from_utf8_lossy(&rx_buf);
+                        log::warn!("Refused command {:?}", command.trim_end_matches('\0'));
+                        Header::new(Encoding::ZHEX, Frame::ZCOMPL)
+                            .with_count(1)
+                            .write_escctl(port, tracker.escctl, config.dialect)?
+                    }
+                    Err(_) => {
+                        stats.crc_failures += 1;
+                        ZNAK_HEADER.write_escctl(port, tracker.escctl, config.dialect)?
+                    }
+                }
+            }
+            // The last request is repeated:
+            Frame::ZNAK if state.0.is_some() => {
+                stats.retransmits += 1;
+                ZRPOS_HEADER.with_count(state.1).write_escctl(
+                    port,
+                    tracker.escctl,
+                    config.dialect,
+                )?
+            }
+            Frame::ZRQINIT => {
+                // The sender has restarted, so the partial file is discarded
+                // and the session starts over. Before the file, the offset
+                // to resume it from is kept.
+                if state.0.is_some() {
+                    if state.1 != 0 && !out.rewind(0)? {
+                        return Err(Error::InvalidData);
+                    }
+                    *state = (None, 0);
+                }
+                skipped = false;
+                tracker = Tracker::new(config);
+                attention = DEFAULT_ATTENTION.to_vec();
+                repositioned = None;
+                Header::zrinit(Encoding::ZHEX, zrinit, config.zrinit_buffer_len()).write_escctl(
+                    port,
+                    tracker.escctl,
+                    config.dialect,
+                )?
+            }
+            _ if state.0.is_none() => Header::zrinit(
                 Encoding::ZHEX,
-                Zrinit::CANCRY | Zrinit::CANOVIO | Zrinit::CANFC32,
-                0,
-            )?,
+                zrinit,
+                config.zrinit_buffer_len(),
+            )
+            .write_escctl(port, tracker.escctl, config.dialect)?,
             _ => (),
         }
     }
 
-    Ok(())
+    stats.subpackets_received = tracker.subpackets;
+    stats.crc32 = tracker.crc32();
+    Ok(stats)
+}
+
+/// Sends the file at `path`, named after its last component.
+///
+/// The file is opened before anything is sent, so that
+/// `Error::SourceUnavailable` leaves the peer untouched.
+pub fn send_file<P>(port: &mut P, path: &Path) -> core::result::Result<TransferStats, Error>
+where
+    P: Read + Write,
+{
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or(InvalidData)?;
+    let mut file = std::fs::File::open(path).or(Err(Error::SourceUnavailable))?;
+    // A directory can be opened, but not read:
+    let metadata = file.metadata().or(Err(Error::SourceUnavailable))?;
+    if !metadata.is_file() {
+        return Err(Error::SourceUnavailable);
+    }
+    let size = u32::try_from(metadata.len()).ok();
+    write(port, &mut file, name, size)
+}
+
+/// Receives a file into the directory `dir`, and returns the paths of the
+/// files created.
+///
+/// The data is first written to a partial file, which is renamed once the
+/// transfer is complete. Only the last component of the name sent by the
+/// peer is used, so that files cannot be placed outside of `dir`.
+pub fn recv_file<P>(port: &mut P, dir: &Path) -> core::result::Result<Vec<PathBuf>, Error>
+where
+    P: Read + Write,
+{
+    recv_file_with(port, dir, &Config::default()).map_err(|err| err.error)
+}
+
+/// Receives a file like `recv_file`, with the options in `config`.
+///
+/// Without an `exists` callback, the files in `dir` are those which exist,
+/// e.g. with `ExistsPolicy::Rename` such a file is received under a new
+/// name. With `ExistsPolicy::Resume`, the data received is appended to the
+/// file in `dir`, also the data kept after an error. The error reports the
+/// path of the data kept as per `Config::keep_partial_on_error`.
+pub fn recv_file_with<P>(
+    port: &mut P,
+    dir: &Path,
+    config: &Config,
+) -> core::result::Result<Vec<PathBuf>, RecvError>
+where
+    P: Read + Write,
+{
+    // Each call, also from other threads, needs a partial file of its own:
+    static PARTIALS: AtomicU32 = AtomicU32::new(0);
+    let (partial, mut out) = loop {
+        let n = PARTIALS.fetch_add(1, Ordering::Relaxed);
+        let partial = dir.join(format!(".zmodem-{}-{}.part", std::process::id(), n));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&partial)
+        {
+            Ok(out) => break (partial, out),
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(_) => return Err(Error::InvalidData.into()),
+        }
+    };
+    let mut state = (None, 0);
+    let config = match &config.exists {
+        None if config.on_exists != ExistsPolicy::Overwrite => {
+            let dir = dir.to_path_buf();
+            let exists = move |info: &FileInfo| {
+                let name = Path::new(&info.name).file_name()?;
+                let metadata = std::fs::metadata(dir.join(name)).ok()?;
+                Some(u32::try_from(metadata.len()).unwrap_or(u32::MAX))
+            };
+            Config {
+                exists: Some(Arc::new(exists)),
+                ..config.clone()
+            }
+        }
+        _ => config.clone(),
+    };
+    let result = read_with(port, &mut state, &mut out, &config);
+    // The partial file holds the data from the offset the sender was asked
+    // to start at:
+    let start = out.metadata().map_or(0, |metadata| {
+        u64::from(state.1).saturating_sub(metadata.len())
+    });
+    drop(out);
+
+    let name = state
+        .0
+        .as_ref()
+        .and_then(|file| Path::new(&file.info().name).file_name());
+    let resumed = config.on_exists == ExistsPolicy::Resume && start != 0;
+    let target = match (&result, name) {
+        (Ok(_), Some(name)) => Some(dir.join(name)),
+        (Err(_), Some(name)) if config.keep_partial_on_error && resumed => Some(dir.join(name)),
+        (Err(_), Some(name)) if config.keep_partial_on_error => {
+            let mut name = name.to_os_string();
+            name.push(".part");
+            Some(dir.join(name))
+        }
+        _ => None,
+    };
+    let kept = target.as_ref().is_some_and(|target| {
+        if resumed {
+            append(&partial, target, start).is_ok()
+        } else {
+            std::fs::rename(&partial, target).is_ok()
+        }
+    });
+    if !kept {
+        let _ = std::fs::remove_file(&partial);
+    }
+    match result {
+        Ok(_) if kept => Ok(target.into_iter().collect()),
+        Ok(_) => Err(Error::InvalidData.into()),
+        Err(Error::Skipped) => Ok(vec![]),
+        Err(error) => Err(RecvError {
+            error,
+            partial: target.filter(|_| kept),
+        }),
+    }
+}
+
+/// Appends the data in `partial` to `target`, which holds the `len` bytes
+/// before it, and removes `partial`.
+fn append(partial: &Path, target: &Path, len: u64) -> std::io::Result<()> {
+    let mut out = std::fs::OpenOptions::new().append(true).open(target)?;
+    // The file has changed since the transfer was resumed:
+    if out.metadata()?.len() != len {
+        return Err(std::io::ErrorKind::InvalidData.into());
+    }
+    std::io::copy(&mut std::fs::File::open(partial)?, &mut out)?;
+    std::fs::remove_file(partial)
+}
+
+/// Remembers whether the port has reached the end of its input, a read has
+/// timed out, or the peer has aborted the session.
+struct EofPort<'a, P> {
+    port: &'a mut P,
+    eof: bool,
+    timed_out: bool,
+    /// Five `CAN` bytes in a row have been read
+    aborted: bool,
+    /// `CAN` bytes read in a row
+    cans: u32,
+    /// `Config::timeout`
+    timeout: Option<Duration>,
+    /// Time of the last byte read or written
+    active: Instant,
+    /// `Config::empty_read_retries`
+    empty_read_retries: u32,
+    /// Reads in a row which returned no data
+    empty_reads: u32,
+}
+
+impl<'a, P> EofPort<'a, P> {
+    fn new(port: &'a mut P, config: &Config) -> Self {
+        Self {
+            port,
+            eof: false,
+            timed_out: false,
+            aborted: false,
+            cans: 0,
+            timeout: config.timeout,
+            active: Instant::now(),
+            empty_read_retries: config.empty_read_retries,
+            empty_reads: 0,
+        }
+    }
+}
+
+impl<P: Read> Read for EofPort<'_, P> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = loop {
+            match self.port.read(buf) {
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock
+                    ) =>
+                {
+                    // Idle, but not for long enough to give up:
+                    if self
+                        .timeout
+                        .is_some_and(|timeout| self.active.elapsed() < timeout)
+                    {
+                        continue;
+                    }
+                    self.timed_out = true;
+                    self.active = Instant::now();
+                    return Err(err);
+                }
+                Ok(0) if !buf.is_empty() && self.empty_reads < self.empty_read_retries => {
+                    self.empty_reads += 1;
+                    std::thread::yield_now();
+                }
+                result => break result?,
+            }
+        };
+        if count == 0 && !buf.is_empty() {
+            self.eof = true;
+        } else {
+            self.active = Instant::now();
+            self.empty_reads = 0;
+        }
+        // CAN is the standard ZDLE, which never follows itself in valid data,
+        // even in a dialect:
+        for &byte in &buf[..count] {
+            self.cans = if byte == ZDLE { self.cans + 1 } else { 0 };
+            self.aborted |= self.cans >= 5;
+        }
+        Ok(count)
+    }
+}
+
+impl<P: Write> Write for EofPort<'_, P> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.active = Instant::now();
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.port.flush()
+    }
 }
 
-/// Writes a ZDATA
+/// Writes a ZDATA, or ZEOF if there is no more data, and returns the offset
+/// after the data sent and whether ZEOF was sent. A nonzero `window` is the buffer length of the receiver,
+/// which limits the data sent before asking for an acknowledgement.
 fn write_zdata<P, F>(
     port: &mut P,
-    file: &mut F,
+    outgoing: &mut Outgoing<F>,
     header: &Header,
-) -> core::result::Result<(), InvalidData>
+    encoding: Encoding,
+    window: u32,
+    buffers: &mut Buffers,
+    tracker: &mut Tracker,
+) -> core::result::Result<(u32, bool), InvalidData>
 where
     P: Read + Write,
     F: Reader,
 {
-    let mut data = [0; SUBPACKET_SIZE as usize];
+    let config = tracker.config;
+    let max_len = config.max_subpacket_len()?;
+    let max_per_ack = config.subpackets_per_ack();
+    let (len, per_ack) = match window {
+        0 => (max_len, max_per_ack),
+        _ => {
+            let len = window.min(max_len);
+            (len, ((window / len) as usize).min(max_per_ack))
+        }
+    };
+    // The data of the next subpacket is read ahead, so that the last one
+    // before the end of the file can ask for an acknowledgement:
+    let [data, ahead] = &mut buffers.data;
+    let mut data = &mut data[..len as usize];
+    let mut ahead = &mut ahead[..len as usize];
     let mut offset: u32 = header.count();
+    // Without a size the receiver could not tell padding from data:
+    let pad = config.fixed_subpacket_len && outgoing.size.is_some();
+    let file = &mut *outgoing.file;
 
     file.seek(offset)?;
-    let mut count: u32 = file.read(&mut data)?;
+    let mut count: u32 = read_full(file, data)?;
     if count == 0 {
-        ZEOF_HEADER.with_count(offset).write(port)?;
-        return Ok(());
+        Header::new(encoding, Frame::ZEOF)
+            .with_count(offset)
+            .write_with(port, config.double_pad, tracker.escctl, config.dialect)?;
+        return Ok((offset, true));
     }
 
-    ZDATA_HEADER.with_count(offset).write(port)?;
-    for _ in 1..SUBPACKET_PER_ACK {
-        write_subpacket(
+    Header::new(encoding, Frame::ZDATA)
+        .with_count(offset)
+        .write_with(port, config.double_pad, tracker.escctl, config.dialect)?;
+    for n in 1..=per_ack {
+        let last = count < len || n == per_ack;
+        let next = if last { 0 } else { read_full(file, ahead)? };
+        let kind = if next == 0 {
+            Packet::ZCRCW
+        } else {
+            Packet::ZCRCG
+        };
+        tracker.update(offset, &data[..count as usize]);
+        #[cfg(feature = "crypto")]
+        config.encrypt(offset, &mut data[..count as usize])?;
+        tracker.wire_bytes += write_subpacket_with(
             port,
-            Encoding::ZBIN32,
-            Packet::ZCRCG,
-            &data[..count as usize],
-        )?;
+            encoding,
+            kind,
+            padded(data, count, pad),
+            &mut buffers.escaped,
+            config.dialect,
+            tracker.escctl,
+        )? as u64;
         offset += count;
-
-        count = file.read(&mut data)?;
-        if count < SUBPACKET_SIZE {
+        if next == 0 {
             break;
         }
+        core::mem::swap(&mut data, &mut ahead);
+        count = next;
     }
+    Ok((offset, false))
+}
 
-    write_subpacket(
-        port,
-        Encoding::ZBIN32,
-        Packet::ZCRCW,
-        &data[..count as usize],
-    )?;
-    Ok(())
+/// Returns the first `count` bytes of `data`, or all of it with the rest
+/// zeroed if `pad` is set and there is any data.
+fn padded(data: &mut [u8], count: u32, pad: bool) -> &[u8] {
+    if pad && count > 0 {
+        data[count as usize..].fill(0);
+        data
+    } else {
+        &data[..count as usize]
+    }
+}
+
+/// Reads from `file` until `buf` is full or the end of file is reached.
+/// Readers such as pipes may return less than requested before the end.
+fn read_full<F>(file: &mut F, buf: &mut [u8]) -> core::result::Result<u32, InvalidData>
+where
+    F: Reader,
+{
+    let mut count = 0;
+    while count < buf.len() {
+        match file.read(&mut buf[count..])? {
+            0 => break,
+            n => count += n as usize,
+        }
+    }
+    Ok(count as u32)
+}
+
+/// Aborts the session by sending the cancel sequence of `sz` and `rz`: ten
+/// `CAN` bytes, which the peer takes as the end of the session, and as many
+/// backspaces to erase them from a terminal which echoes them. The session
+/// cannot go on afterwards, and so the port is only good for a new one.
+/// `Config::cancel` does the same from inside a transfer.
+pub fn send_cancel<P>(port: &mut P) -> core::result::Result<(), Error>
+where
+    P: Write,
+{
+    port.write_all(&ABORT).or(Err(Error::InvalidData))?;
+    port.flush().or(Err(Error::InvalidData))
+}
+
+/// Checks that the first `len` bytes written to `out` have the CRC-32 `crc`,
+/// by reading them back. This catches corruption after the data has passed
+/// the subpacket CRCs, e.g. in the storage behind `out`.
+pub fn verify<W>(out: &mut W, len: u32, crc: u32) -> core::result::Result<(), Error>
+where
+    W: ReadableWriter,
+{
+    let mut buf = [0; SUBPACKET_SIZE as usize];
+    let mut digest = CRC32.digest();
+    let mut offset = 0;
+    while offset < len {
+        let count = (len - offset).min(SUBPACKET_SIZE) as usize;
+        match out.read_at(offset, &mut buf[..count])? {
+            0 => return Err(Error::InvalidData),
+            n => {
+                digest.update(&buf[..n as usize]);
+                offset += n;
+            }
+        }
+    }
+    if digest.finalize() == crc {
+        Ok(())
+    } else {
+        Err(Error::InvalidData)
+    }
+}
+
+/// Computes the CRC-32 of the first `len` bytes of `file`, or of the whole
+/// file if `len` is zero, as requested with `ZCRC`.
+fn file_crc<F>(file: &mut F, len: u32) -> core::result::Result<u32, InvalidData>
+where
+    F: Reader,
+{
+    let mut buf = [0; SUBPACKET_SIZE as usize];
+    let mut digest = CRC32.digest();
+    let mut offset = 0;
+    file.seek(0)?;
+    while len == 0 || offset < len {
+        let count = match len {
+            0 => SUBPACKET_SIZE,
+            _ => (len - offset).min(SUBPACKET_SIZE),
+        };
+        match file.read(&mut buf[..count as usize])? {
+            0 => break,
+            n => {
+                digest.update(&buf[..n as usize]);
+                offset += n;
+            }
+        }
+    }
+    Ok(digest.finalize())
 }
 
-/// Reads a ZDATA packet
+/// Reads a ZDATA packet. Returns `false` if the sender has to be stopped
+/// and asked to resend from `count`, because a subpacket was garbled, the
+/// output was full, or the sender went past the advertised buffer length
+/// without waiting for an acknowledgement.
 fn read_zdata<P, F>(
     encoding: u8,
     count: &mut u32,
+    limit: Option<u32>,
     port: &mut P,
     file: &mut F,
-) -> core::result::Result<(), InvalidData>
+    stats: &mut TransferStats,
+    tracker: &mut Tracker,
+) -> core::result::Result<bool, InvalidData>
 where
     P: Write + Read,
     F: Writer,
 {
     let mut buf = RxBuffer::new();
+    // Only a buffer length set by the user limits the data in flight, as
+    // the default of `small` only limits the subpacket size:
+    let window = match tracker.config.buffer_len {
+        Some(_) => tracker.config.zrinit_buffer_len() as usize,
+        None => 0,
+    };
+    let mut unacked = 0;
 
     loop {
         buf.clear();
         let encoding = Encoding::try_from(encoding)?;
-        let zcrc = match read_subpacket(port, encoding, &mut buf) {
+        let zcrc = match read_subpacket(port, encoding, &mut buf, tracker.config.dialect) {
             Err(_) => {
-                ZRPOS_HEADER.with_count(*count).write(port)?;
-                return Ok(());
+                stats.crc_failures += 1;
+                return Ok(false);
             }
             Ok(zcrc) => zcrc,
         };
-        file.write(&buf)?;
-        *count += buf.len() as u32;
+        // Anything past the announced size is padding:
+        let len = limit.map_or(buf.len(), |limit| {
+            buf.len().min(limit.saturating_sub(*count) as usize)
+        });
+        unacked += len;
+        if window != 0 && unacked > window {
+            log::warn!("Sender went past the buffer length {}", window);
+            return Ok(false);
+        }
+        #[cfg(feature = "crypto")]
+        tracker.config.decrypt(*count, &mut buf[..len])?;
+        let written = file.try_write(&buf[..len])?;
+        tracker.update(*count, &buf[..written]);
+        *count += written as u32;
+        if written < len {
+            // The rest is asked for again once there is room for it:
+            log::debug!("Output full at {}", *count);
+            return Ok(false);
+        }
         match zcrc {
             Packet::ZCRCW => {
-                ZACK_HEADER.with_count(*count).write(port)?;
-                return Ok(());
+                ZACK_HEADER.with_count(*count).write_escctl(
+                    port,
+                    tracker.escctl,
+                    tracker.config.dialect,
+                )?;
+                return Ok(true);
             }
-            Packet::ZCRCE => return Ok(()),
+            Packet::ZCRCE => return Ok(true),
             Packet::ZCRCQ => {
-                ZACK_HEADER.with_count(*count).write(port)?;
+                ZACK_HEADER.with_count(*count).write_escctl(
+                    port,
+                    tracker.escctl,
+                    tracker.config.dialect,
+                )?;
+                unacked = 0;
             }
             Packet::ZCRCG => log::debug!("ZCRCG"),
         }
     }
 }
 
+/// Skips the file being received with `ZSKIP` after writing it has failed,
+/// and returns true if `Config::continue_on_file_error` lets the session go
+/// on with the next file.
+fn skip_failed<P, S>(
+    port: &mut P,
+    state: &mut (Option<File>, u32),
+    out: &mut S,
+    tracker: &Tracker,
+) -> core::result::Result<bool, Error>
+where
+    P: Write,
+    S: Sink,
+{
+    let file = match &state.0 {
+        Some(file) if tracker.config.continue_on_file_error => file,
+        _ => return Ok(false),
+    };
+    if !out.fail(file)? {
+        return Ok(false);
+    }
+    log::warn!("Skipping {:?}, which failed", file.info.name);
+    *state = (None, 0);
+    ZSKIP_HEADER.write_escctl(port, tracker.escctl, tracker.config.dialect)?;
+    Ok(true)
+}
+
+/// Checks the end offset announced by `ZEOF` against the number of bytes
+/// received. Returns `false` if data is still missing, and an error if more
+/// data was received than the sender has sent.
+fn check_zeof(offset: u32, received: u32) -> core::result::Result<bool, InvalidData> {
+    match offset.cmp(&received) {
+        core::cmp::Ordering::Equal => Ok(true),
+        core::cmp::Ordering::Greater => {
+            log::warn!("ZEOF ahead of data: frame({}) > recv({})", offset, received);
+            Ok(false)
+        }
+        core::cmp::Ordering::Less => {
+            log::error!("ZEOF data overrun: frame({}) < recv({})", offset, received);
+            Err(InvalidData)
+        }
+    }
+}
+
 /// Skips (ZPAD, [ZPAD,] ZDLE) sequence.
-fn read_zpad<P>(port: &mut P) -> core::result::Result<(), InvalidData>
+fn read_zpad<P>(port: &mut P, dialect: Dialect) -> core::result::Result<(), InvalidData>
 where
     P: Read,
 {
-    if read_byte(port)? != ZPAD {
+    if read_byte(port)? != dialect.zpad {
         return Err(InvalidData);
     }
 
     let mut b = read_byte(port)?;
-    if b == ZPAD {
+    if b == dialect.zpad {
         b = read_byte(port)?;
     }
 
-    if b == ZDLE {
+    if b == dialect.zdle {
         return Ok(());
     }
 
@@ -743,6 +3401,22 @@ fn read_subpacket<P>(
     port: &mut P,
     encoding: Encoding,
     buf: &mut RxBuffer,
+    dialect: Dialect,
+) -> core::result::Result<Packet, InvalidData>
+where
+    P: Read,
+{
+    read_subpacket_with(port, encoding, buf, dialect, |_| true)
+}
+
+/// Reads a subpacket like `read_subpacket`, but fails as soon as `accept`
+/// returns false for the data read so far.
+fn read_subpacket_with<P>(
+    port: &mut P,
+    encoding: Encoding,
+    buf: &mut RxBuffer,
+    dialect: Dialect,
+    accept: impl Fn(&[u8]) -> bool,
 ) -> core::result::Result<Packet, InvalidData>
 where
     P: Read,
@@ -751,45 +3425,87 @@ where
 
     loop {
         let byte = read_byte(port)?;
-        if byte == ZDLE {
+        if byte == dialect.zdle {
             let byte = read_byte(port)?;
             if let Ok(kind) = Packet::try_from(byte) {
-                buf.push(kind as u8);
+                push_byte(buf, kind as u8)?;
                 result = kind;
                 break;
             } else {
-                buf.push(UNZDLE_TABLE[byte as usize]);
+                push_byte(buf, UNZDLE_TABLE[byte as usize])?;
             }
         } else {
-            buf.push(byte);
+            push_byte(buf, byte)?;
+        }
+        if !accept(buf) {
+            return Err(InvalidData);
         }
     }
 
-    let crc_len = if encoding == Encoding::ZBIN32 { 4 } else { 2 };
+    let crc_len = encoding.crc_len();
     let mut crc = [0u8; 4];
     for b in crc.iter_mut().take(crc_len) {
-        *b = read_byte_unescaped(port)?;
+        *b = read_byte_unescaped(port, dialect)?;
     }
     check_crc(buf, &crc[..crc_len], encoding)?;
 
     // Pop ZCRC
-    buf.pop().unwrap();
+    buf.pop().ok_or(InvalidData)?;
     Ok(result)
 }
 
-fn write_subpacket<P>(
-    port: &mut P,
+/// Parses the escaped subpacket at the start of `data`, and returns its
+/// terminator, the unescaped payload and the number of bytes consumed.
+pub fn parse_subpacket(
+    data: &[u8],
+    encoding: Encoding,
+) -> core::result::Result<(Packet, Vec<u8>, usize), InvalidData> {
+    let mut rest = data;
+    let mut buf = RxBuffer::new();
+    let kind = read_subpacket(&mut rest, encoding, &mut buf, Dialect::default())?;
+    Ok((kind, buf.to_vec(), data.len() - rest.len()))
+}
+
+/// Appends a byte to `buf`, failing instead of panicking when a subpacket
+/// longer than the buffer is received.
+fn push_byte(buf: &mut RxBuffer, byte: u8) -> core::result::Result<(), InvalidData> {
+    match buf.try_push(byte) {
+        None => Ok(()),
+        Some(_) => Err(InvalidData),
+    }
+}
+
+/// Writes a subpacket in `dialect`, escaping it in `buf`, and returns the
+/// number of bytes written. With `escctl`, all control characters are
+/// escaped, for a receiver which asked for it in `ZRINIT`.
+fn write_subpacket_with<P>(
+    port: &mut P,
     encoding: Encoding,
     kind: Packet,
     data: &[u8],
-) -> core::result::Result<(), InvalidData>
+    buf: &mut [u8; ESCAPED_SIZE],
+    dialect: Dialect,
+    escctl: bool,
+) -> core::result::Result<usize, InvalidData>
 where
     P: Write,
 {
     let kind = kind as u8;
-    let mut buf = [0u8; (SUBPACKET_SIZE * 2) as usize];
-    let mut len = escape_mem(data, &mut buf[0..(SUBPACKET_SIZE * 2) as usize]);
-    port.write_all(&buf[..len]).or(Err(InvalidData))?;
+    let mut len;
+    let mut written = 0;
+    // Escapes in chunks so that the scratch buffer does not need to scale
+    // with the subpacket size:
+    for chunk in data.chunks(SUBPACKET_SIZE as usize) {
+        // Most text needs no escaping, and can be written as is:
+        if chunk.iter().all(|b| escape(*b, dialect.zdle, escctl) == *b) {
+            port.write_all(chunk).or(Err(InvalidData))?;
+            written += chunk.len();
+            continue;
+        }
+        len = escape_mem(chunk, &mut buf[..], dialect.zdle, escctl);
+        port.write_all(&buf[..len]).or(Err(InvalidData))?;
+        written += len;
+    }
     match encoding {
         Encoding::ZBIN32 => {
             let mut digest = CRC32.digest();
@@ -797,7 +3513,9 @@ where
             digest.update(&[kind]);
             len = escape_mem(
                 &digest.finalize().to_le_bytes(),
-                &mut buf[0..(SUBPACKET_SIZE * 2) as usize],
+                &mut buf[..],
+                dialect.zdle,
+                escctl,
             )
         }
         Encoding::ZBIN => {
@@ -806,16 +3524,17 @@ where
             digest.update(&[kind]);
             len = escape_mem(
                 &digest.finalize().to_be_bytes(),
-                &mut buf[0..(SUBPACKET_SIZE * 2) as usize],
+                &mut buf[..],
+                dialect.zdle,
+                escctl,
             )
         }
-        Encoding::ZHEX => {
-            unimplemented!()
-        }
+        // Data subpackets are never hex encoded
+        Encoding::ZHEX => return Err(InvalidData),
     };
-    port.write_all(&[ZDLE, kind]).or(Err(InvalidData))?;
+    port.write_all(&[dialect.zdle, kind]).or(Err(InvalidData))?;
     port.write_all(&buf[..len]).or(Err(InvalidData))?;
-    Ok(())
+    Ok(written + 2 + len)
 }
 
 fn check_crc(data: &[u8], crc: &[u8], encoding: Encoding) -> core::result::Result<(), InvalidData> {
@@ -831,26 +3550,22 @@ fn check_crc(data: &[u8], crc: &[u8], encoding: Encoding) -> core::result::Resul
 }
 
 fn make_crc(data: &[u8], out: &mut [u8], encoding: Encoding) -> usize {
+    let crc_len = encoding.crc_len();
     match encoding {
-        Encoding::ZBIN32 => {
-            let crc = CRC32.checksum(data).to_le_bytes();
-            out[..4].copy_from_slice(&crc[..4]);
-            4
-        }
-        _ => {
-            let crc = CRC16.checksum(data).to_be_bytes();
-            out[..2].copy_from_slice(&crc[..2]);
-            2
+        Encoding::ZBIN32 => out[..crc_len].copy_from_slice(&CRC32.checksum(data).to_le_bytes()),
+        Encoding::ZBIN | Encoding::ZHEX => {
+            out[..crc_len].copy_from_slice(&CRC16.checksum(data).to_be_bytes())
         }
     }
+    crc_len
 }
 
-fn read_byte_unescaped<P>(port: &mut P) -> core::result::Result<u8, InvalidData>
+fn read_byte_unescaped<P>(port: &mut P, dialect: Dialect) -> core::result::Result<u8, InvalidData>
 where
     P: Read,
 {
     let b = read_byte(port)?;
-    Ok(if b == ZDLE {
+    Ok(if b == dialect.zdle {
         UNZDLE_TABLE[read_byte(port)? as usize]
     } else {
         b
@@ -867,12 +3582,89 @@ where
         .or(Err(InvalidData))
 }
 
-fn escape_mem(src: &[u8], dst: &mut [u8]) -> usize {
+/// Returns the number of data subpackets needed to send `size` bytes to a
+/// receiver using `config`, when none has to be sent again. An empty file
+/// needs none, as the end of the file is sent in `ZEOF`. Fails like sending
+/// on a `Config::subpacket_len` out of range.
+pub fn subpacket_count(size: u64, config: &Config) -> core::result::Result<u64, InvalidData> {
+    let max_len = config.max_subpacket_len()?;
+    let len = match config.zrinit_buffer_len() as u32 {
+        0 => max_len,
+        window => window.min(max_len),
+    };
+    Ok(size.div_ceil(len as u64))
+}
+
+/// What this build of the crate supports, as returned by `capabilities`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capabilities {
+    /// Encodings of the headers read and written. Data subpackets are sent
+    /// only in the binary ones.
+    pub encodings: &'static [Encoding],
+    /// Length of the largest data subpacket sent, which the `small` feature
+    /// reduces
+    pub subpacket_size: u32,
+    /// Flags which the receiver can advertise in `ZRINIT`
+    pub zrinit: Zrinit,
+    /// Whether `Config::cipher` is available, with the `crypto` feature
+    pub crypto: bool,
+    /// Whether `MmapReader` is available, with the `memmap2` feature
+    pub mmap: bool,
+    /// Whether `LossyLink` is available, with the `lossy-link` feature
+    pub lossy_link: bool,
+}
+
+/// Returns the version of the crate.
+pub fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+/// Returns what this build of the crate supports, which depends on the
+/// features it was compiled with.
+pub fn capabilities() -> Capabilities {
+    let mut zrinit = Zrinit::CANOVIO | Zrinit::CANFC32;
+    if cfg!(feature = "crypto") {
+        zrinit |= Zrinit::CANCRY;
+    }
+    Capabilities {
+        encodings: ENCODINGS,
+        subpacket_size: SUBPACKET_SIZE,
+        zrinit,
+        crypto: cfg!(feature = "crypto"),
+        mmap: cfg!(feature = "memmap2"),
+        lossy_link: cfg!(feature = "lossy-link"),
+    }
+}
+
+/// Returns the length of `data` after ZDLE escaping.
+pub fn escaped_size(data: &[u8]) -> usize {
+    data.iter()
+        .map(|b| if ZDLE_TABLE[*b as usize] != *b { 2 } else { 1 })
+        .sum()
+}
+
+/// Returns what follows `zdle` in place of `byte`, or `byte` if it is not
+/// escaped. The standard `ZDLE` stays escaped in a dialect too. With
+/// `escctl`, all control characters are escaped, also with the 8th bit set.
+fn escape(byte: u8, zdle: u8, escctl: bool) -> u8 {
+    if byte == zdle || (escctl && byte & 0x60 == 0) {
+        byte ^ 0x40
+    } else {
+        ZDLE_TABLE[byte as usize]
+    }
+}
+
+/// Returns the largest possible length of `n` bytes after ZDLE escaping.
+pub const fn worst_case_escaped_size(n: usize) -> usize {
+    2 * n
+}
+
+fn escape_mem(src: &[u8], dst: &mut [u8], zdle: u8, escctl: bool) -> usize {
     let mut i = 0;
     for b in src {
-        let b_e = ZDLE_TABLE[*b as usize];
+        let b_e = escape(*b, zdle, escctl);
         if b_e != *b {
-            dst[i] = ZDLE;
+            dst[i] = zdle;
             i += 1;
         }
         dst[i] = b_e;
@@ -884,9 +3676,78 @@ fn escape_mem(src: &[u8], dst: &mut [u8]) -> usize {
 #[cfg(test)]
 mod tests {
     use crate::{
-        read_subpacket, read_zpad, write_subpacket, Encoding, Frame, Header, InvalidData, Packet,
-        RxBuffer, XON, ZDLE, ZPAD,
+        escape, escape_mem, escaped_size, parse_subpacket, read, read_batch, read_seekable_with,
+        read_subpacket, read_with, read_zpad, send_cancel, subpacket_count, verify,
+        worst_case_escaped_size, write, write_batch, write_subpacket_with, write_with,
+        write_with_buffers, Backoff, BufferedSequentialReader, Buffers, Completion, Config,
+        Dialect, Encoding, Error, ExistsPolicy, Exponential, File, FileInfo, FileOutcome, Fixed,
+        Frame, Header, InvalidData, Packet, ReadableWriter, RxBuffer, Zrinit, Zsinit, ZsinitParams,
+        ABORT, CRC32, DEFAULT_ATTENTION, ESCAPED_SIZE, RX_BUFFER_SIZE, SUBPACKET_SIZE,
+        UNZDLE_TABLE, XON, ZACK_HEADER, ZDLE, ZFIN_HEADER, ZMCRC, ZNAK_HEADER, ZPAD, ZRPOS_HEADER,
+        ZRQINIT_HEADER, ZSKIP_HEADER, ZTCRYPT,
     };
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    const ZDATA_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZDATA);
+    const ZEOF_HEADER: Header = Header::new(Encoding::ZBIN32, Frame::ZEOF);
+
+    /// Port reading from a fixed input and collecting the output.
+    struct Port<'a>(&'a [u8], Vec<u8>);
+
+    impl Read for Port<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Port<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Returns the sender side of a session transferring `data`.
+    fn sender_transcript(data: &[u8], eof: u32) -> Vec<u8> {
+        let mut out = vec![];
+        Header::write_zfile(&mut out, "test", data.len() as u32).unwrap();
+        ZDATA_HEADER.write(&mut out).unwrap();
+        write_subpacket(&mut out, Encoding::ZBIN32, Packet::ZCRCW, data).unwrap();
+        ZEOF_HEADER.with_count(eof).write(&mut out).unwrap();
+        ZFIN_HEADER.write(&mut out).unwrap();
+        out
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle)
+    }
+
+    fn write_subpacket<P>(
+        port: &mut P,
+        encoding: Encoding,
+        kind: Packet,
+        data: &[u8],
+    ) -> core::result::Result<(), InvalidData>
+    where
+        P: Write,
+    {
+        let mut buf = [0; ESCAPED_SIZE];
+        write_subpacket_with(
+            port,
+            encoding,
+            kind,
+            data,
+            &mut buf,
+            Dialect::default(),
+            false,
+        )?;
+        Ok(())
+    }
 
     #[rstest::rstest]
     #[case(Encoding::ZBIN, Frame::ZRQINIT, &[ZPAD, ZDLE, Encoding::ZBIN as u8, 0, 0, 0, 0, 0, 0, 0])]
@@ -898,6 +3759,38 @@ mod tests {
         assert_eq!(port, expected);
     }
 
+    #[rstest::rstest]
+    #[case(&[])]
+    #[case(b"hello")]
+    #[case(&[0xff; 100])]
+    #[case(&[ZDLE, XON, 0x13, 0x90, 0x91, 0x93, 0x0d, 0x8d, 0x7f])]
+    #[case(&(0..=255).collect::<Vec<u8>>())]
+    pub fn test_escaped_size(#[case] data: &[u8]) {
+        let mut escaped = vec![0; worst_case_escaped_size(data.len())];
+        let len = escape_mem(data, &mut escaped, ZDLE, false);
+        assert_eq!(escaped_size(data), len);
+    }
+
+    #[rstest::rstest]
+    #[case(Encoding::ZBIN)]
+    #[case(Encoding::ZBIN32)]
+    #[case(Encoding::ZHEX)]
+    pub fn test_header_double_pad(#[case] encoding: Encoding) {
+        let header = Header::new(encoding, Frame::ZDATA).with_count(0x1234);
+        let mut port = vec![];
+        header.write_padded(&mut port, true).unwrap();
+        assert_eq!(port[..3], [ZPAD, ZPAD, ZDLE]);
+
+        let mut single = vec![];
+        header.write(&mut single).unwrap();
+        let prefix = if encoding == Encoding::ZHEX { 3 } else { 2 };
+        assert_eq!(port[3..], single[prefix..]);
+
+        let mut input = port.as_slice();
+        read_zpad(&mut input, Dialect::default()).unwrap();
+        assert_eq!(Header::read(&mut input).unwrap(), header);
+    }
+
     #[rstest::rstest]
     #[case(Encoding::ZBIN, Frame::ZRQINIT, &[1, 1, 1, 1], &[ZPAD, ZDLE, Encoding::ZBIN as u8, 0, 1, 1, 1, 1, 98, 148])]
     #[case(Encoding::ZHEX, Frame::ZRQINIT, &[1, 1, 1, 1], &[ZPAD, ZPAD, ZDLE, Encoding::ZHEX as u8, b'0', b'0', b'0', b'1', b'0', b'1', b'0', b'1', b'0', b'1', 54, 50, 57, 52, b'\r', b'\n', XON])]
@@ -925,14 +3818,14 @@ mod tests {
         #[case] port: &[u8],
         #[case] expected: core::result::Result<(), InvalidData>,
     ) {
-        let result = read_zpad(&mut port.to_vec().as_slice());
+        let result = read_zpad(&mut port.to_vec().as_slice(), Dialect::default());
         if result.is_err() {
             assert_eq!(result.unwrap_err(), expected.unwrap_err());
         }
     }
 
     #[rstest::rstest]
-    #[case(&[Encoding::ZHEX as u8, b'0', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'a', b'7', b'5', b'2'], &Header::new(Encoding::ZHEX, Frame::ZRINIT).with_flags(&[0x1, 0x2, 0x3, 0x4]))]
+    #[case(&[Encoding::ZHEX as u8, b'0', b'1', b'0', b'1', b'0', b'2', b'0', b'3', b'0', b'4', b'a', b'7', b'5', b'2', b'\r', 0x8a, XON], &Header::new(Encoding::ZHEX, Frame::ZRINIT).with_flags(&[0x1, 0x2, 0x3, 0x4]))]
     #[case(&[Encoding::ZBIN as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0xa6, 0xcb], &Header::new(Encoding::ZBIN, Frame::ZRINIT).with_flags(&[0xa, 0xb, 0xc, 0xd]))]
     #[case(&[Encoding::ZBIN32 as u8, Frame::ZRINIT as u8, 0xa, 0xb, 0xc, 0xd, 0x99, 0xe2, 0xae, 0x4a], &Header::new(Encoding::ZBIN32, Frame::ZRINIT).with_flags(&[0xa, 0xb, 0xc, 0xd]))]
     #[case(&[Encoding::ZBIN as u8, Frame::ZRINIT as u8, 0xa, ZDLE, b'l', 0xd, ZDLE, b'm', 0x5e, 0x6f], &Header::new(Encoding::ZBIN, Frame::ZRINIT).with_flags(&[0xa, 0x7f, 0xd, 0xff]))]
@@ -941,6 +3834,29 @@ mod tests {
         assert_eq!(&mut Header::read(&mut input.as_slice()).unwrap(), expected);
     }
 
+    #[rstest::rstest]
+    #[case(Frame::ZRQINIT, false, true, false, false)]
+    #[case(Frame::ZRINIT, false, true, false, false)]
+    #[case(Frame::ZFILE, false, false, false, false)]
+    #[case(Frame::ZRPOS, false, true, false, true)]
+    #[case(Frame::ZDATA, true, false, false, true)]
+    #[case(Frame::ZEOF, false, true, false, true)]
+    #[case(Frame::ZFIN, false, true, true, false)]
+    #[case(Frame::ZCAN, false, true, true, false)]
+    pub fn test_header_classification(
+        #[case] kind: Frame,
+        #[case] is_data_frame: bool,
+        #[case] is_control: bool,
+        #[case] is_terminal: bool,
+        #[case] carries_count: bool,
+    ) {
+        let header = Header::new(Encoding::ZHEX, kind);
+        assert_eq!(header.is_data_frame(), is_data_frame);
+        assert_eq!(header.is_control(), is_control);
+        assert_eq!(header.is_terminal(), is_terminal);
+        assert_eq!(header.carries_count(), carries_count);
+    }
+
     #[rstest::rstest]
     #[case(Encoding::ZBIN, Packet::ZCRCE, &[])]
     #[case(Encoding::ZBIN, Packet::ZCRCW, &[0x00])]
@@ -954,9 +3870,2307 @@ mod tests {
         write_subpacket(&mut port, encoding, kind, data).unwrap();
         let mut rx_buf = RxBuffer::new();
         assert_eq!(
-            read_subpacket(&mut port.as_slice(), encoding, &mut rx_buf).unwrap(),
+            read_subpacket(
+                &mut port.as_slice(),
+                encoding,
+                &mut rx_buf,
+                Dialect::default()
+            )
+            .unwrap(),
             kind
         );
         assert_eq!(&rx_buf[..], data);
     }
+
+    #[test]
+    pub fn test_unzdle_8th_bit() {
+        // Only these bytes with the 8th bit set can be sent escaped:
+        let restored: Vec<u8> = (0x80..=0xff)
+            .filter(|&b| UNZDLE_TABLE[(b ^ 0x40) as usize] == b || escape(b, ZDLE, false) != b)
+            .collect();
+        let expected: Vec<u8> = (0x80..=0x9f).chain([0xff]).collect();
+        assert_eq!(restored, expected);
+        assert_eq!(UNZDLE_TABLE[escape(0xff, ZDLE, false) as usize], 0xff);
+    }
+
+    #[rstest::rstest]
+    pub fn test_write_read_subpacket_escctl(
+        #[values(Encoding::ZBIN, Encoding::ZBIN32)] encoding: Encoding,
+    ) {
+        let data: Vec<u8> = (0..=0xff).collect();
+        let mut port = vec![];
+        let mut buf = [0; ESCAPED_SIZE];
+        let dialect = Dialect::default();
+        write_subpacket_with(
+            &mut port,
+            encoding,
+            Packet::ZCRCW,
+            &data,
+            &mut buf,
+            dialect,
+            true,
+        )
+        .unwrap();
+        // Only ZDLE is left of the control characters:
+        assert!(port.iter().all(|&b| b & 0x60 != 0 || b == ZDLE));
+
+        let mut rx_buf = RxBuffer::new();
+        assert_eq!(
+            read_subpacket(&mut port.as_slice(), encoding, &mut rx_buf, dialect).unwrap(),
+            Packet::ZCRCW
+        );
+        assert_eq!(&rx_buf[..], data);
+    }
+
+    #[rstest::rstest]
+    #[case(Zrinit::empty(), false)]
+    #[case(Zrinit::ESCCTL, true)]
+    pub fn test_write_escctl(#[case] flags: Zrinit, #[case] escctl: bool) {
+        let data: Vec<u8> = (0..0x20).chain(0x80..0xa0).collect();
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO | flags;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(64).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data[..]);
+        let stats = write(&mut port, &mut file, "test", Some(64)).unwrap();
+        assert_eq!(stats.negotiated.unwrap().escctl, escctl);
+
+        // Everything after ZRQINIT, which is sent before the receiver asks:
+        let mut zrqinit = vec![];
+        ZRQINIT_HEADER.write(&mut zrqinit).unwrap();
+        let sent = &port.1[zrqinit.len()..];
+        assert_eq!(sent.iter().all(|&b| b & 0x60 != 0 || b == ZDLE), escctl);
+
+        let mut port = Port(&port.1, vec![]);
+        let mut out = vec![];
+        read(&mut port, &mut (None, 0), &mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[rstest::rstest]
+    // Worst case, every byte is escaped:
+    #[case(vec![ZDLE; 8192])]
+    // Nothing is escaped:
+    #[case(b"hello world\n".repeat(700))]
+    // Only the last chunk is escaped:
+    #[case([b"hello world\n".repeat(700), vec![XON]].concat())]
+    pub fn test_write_large_subpacket(#[case] data: Vec<u8>) {
+        let mut port = vec![];
+        write_subpacket(&mut port, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+
+        let mut expected = vec![0; data.len() * 2];
+        let len = escape_mem(&data, &mut expected, ZDLE, false);
+        expected.truncate(len);
+        expected.extend_from_slice(&[ZDLE, Packet::ZCRCW as u8]);
+        let mut digest = CRC32.digest();
+        digest.update(&data);
+        digest.update(&[Packet::ZCRCW as u8]);
+        let mut crc = [0; 8];
+        let len = escape_mem(&digest.finalize().to_le_bytes(), &mut crc, ZDLE, false);
+        expected.extend_from_slice(&crc[..len]);
+        assert_eq!(port, expected);
+    }
+
+    #[rstest::rstest]
+    #[case(100, Ok(()), false)]
+    #[case(101, Ok(()), true)]
+    #[case(99, Err(Error::InvalidData), false)]
+    pub fn test_read_zeof(
+        #[case] eof: u32,
+        #[case] expected: core::result::Result<(), Error>,
+        #[case] repositioned: bool,
+    ) {
+        let data = [0xa5; 100];
+        let input = sender_transcript(&data, eof);
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        assert_eq!(
+            read(&mut port, &mut (None, 0), &mut out).map(|_| ()),
+            expected
+        );
+        assert_eq!(out, data);
+
+        let mut zrpos = vec![];
+        ZRPOS_HEADER.with_count(100).write(&mut zrpos).unwrap();
+        assert_eq!(contains(&port.1, &zrpos), repositioned);
+    }
+
+    #[rstest::rstest]
+    #[case(Some(Frame::ZRINIT), Completion::Clean, Some(100))]
+    // The receiver goes away after ZEOF:
+    #[case(None, Completion::PeerClosedEarly, None)]
+    #[case(Some(Frame::ZCAN), Completion::CanceledRemote, None)]
+    #[case(Some(Frame::ZABORT), Completion::CanceledRemote, None)]
+    pub fn test_write_completion(
+        #[case] last: Option<Frame>,
+        #[case] expected: Completion,
+        #[case] peer_received_bytes: Option<u64>,
+    ) {
+        let data = [0xa5; 100];
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        if let Some(kind) = last {
+            Header::new(Encoding::ZHEX, kind).write(&mut input).unwrap();
+            ZFIN_HEADER.write(&mut input).unwrap();
+        }
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data[..]);
+        let stats = write(&mut port, &mut file, "test", Some(100)).unwrap();
+        assert_eq!(stats.completion, expected);
+        assert_eq!(stats.peer_received_bytes, peer_received_bytes);
+    }
+
+    #[rstest::rstest]
+    #[case(None, Ok(1))]
+    #[case(Some(100), Ok(3))]
+    #[case(Some(SUBPACKET_SIZE), Ok(1))]
+    #[case(Some(0), Err(Error::InvalidData))]
+    #[case(Some(SUBPACKET_SIZE + 1), Err(Error::InvalidData))]
+    pub fn test_write_subpacket_len(
+        #[case] subpacket_len: Option<u32>,
+        #[case] expected: core::result::Result<u64, Error>,
+    ) {
+        let data = [0xa5; 250];
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(250).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            subpacket_len,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data[..]);
+        let stats = write_with(&mut port, &mut file, "test", Some(250), &config);
+        assert_eq!(stats.map(|stats| stats.subpackets_sent), expected);
+        assert_eq!(expected.is_err(), port.1.is_empty());
+        assert_eq!(subpacket_count(250, &config).ok(), expected.ok());
+    }
+
+    #[rstest::rstest]
+    #[case(None, Some(128), Ok(1))]
+    #[case(Some(5), Some(250), Ok(1))]
+    #[case(Some(2), None, Ok(3))]
+    #[case(Some(5), Some(249), Err(Error::BadWindow))]
+    #[case(Some(0), None, Err(Error::BadWindow))]
+    #[case(Some(u32::MAX), None, Err(Error::BadWindow))]
+    pub fn test_write_subpackets_per_ack(
+        #[case] subpackets_per_ack: Option<u32>,
+        #[case] buffer_len: Option<u16>,
+        #[case] expected: core::result::Result<usize, Error>,
+    ) {
+        let data = [0xa5; 250];
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        for offset in [100, 200, 250] {
+            ZACK_HEADER.with_count(offset).write(&mut input).unwrap();
+        }
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            subpacket_len: Some(50),
+            subpackets_per_ack,
+            buffer_len,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data[..]);
+        let result = write_with(&mut port, &mut file, "test", Some(250), &config);
+        let zcrcw = port.1.windows(2).filter(|w| *w == [ZDLE, b'k']).count();
+        // Less the ZCRCW of ZFILE:
+        assert_eq!(result.map(|_| zcrcw - 1), expected);
+        assert_eq!(expected.is_err(), port.1.is_empty());
+    }
+
+    #[rstest::rstest]
+    #[case(100, Ok(()))]
+    #[case(101, Err(Error::BadReposition))]
+    #[case(u32::MAX, Err(Error::BadReposition))]
+    pub fn test_write_zrpos_past_eof(
+        #[case] offset: u32,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        let data = [0xa5; 100];
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZRPOS_HEADER.with_count(offset).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data[..]);
+        assert_eq!(
+            write(&mut port, &mut file, "test", Some(100)).map(|_| ()),
+            expected
+        );
+
+        let mut zeof = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZEOF)
+            .with_count(offset)
+            .write(&mut zeof)
+            .unwrap();
+        assert_eq!(contains(&port.1, &zeof), expected.is_ok());
+        assert_eq!(port.1.ends_with(&ABORT), expected.is_err());
+    }
+
+    #[rstest::rstest]
+    #[case(SUBPACKET_SIZE as usize * 16, Ok(()))]
+    #[case(SUBPACKET_SIZE as usize, Err(Error::InvalidData))]
+    pub fn test_write_sequential_rewind(
+        #[case] window: usize,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        let data = vec![0xa5; SUBPACKET_SIZE as usize * 12];
+        let rewind = SUBPACKET_SIZE * 4;
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZRPOS_HEADER.with_count(rewind).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = BufferedSequentialReader::new(&data[..], window);
+        assert_eq!(
+            write(&mut port, &mut file, "test", None).map(|_| ()),
+            expected
+        );
+
+        let mut zdata = vec![];
+        ZDATA_HEADER.with_count(rewind).write(&mut zdata).unwrap();
+        assert_eq!(contains(&port.1, &zdata), expected.is_ok());
+    }
+
+    #[rstest::rstest]
+    #[case(Encoding::ZBIN, 2)]
+    #[case(Encoding::ZHEX, 2)]
+    #[case(Encoding::ZBIN32, 4)]
+    pub fn test_crc_len(#[case] encoding: Encoding, #[case] expected: usize) {
+        assert_eq!(encoding.crc_len(), expected);
+
+        let header = Header::new(encoding, Frame::ZRPOS).with_count(0x12345678);
+        let mut port = vec![];
+        header.write(&mut port).unwrap();
+        let mut port = port.as_slice();
+        read_zpad(&mut port, Dialect::default()).unwrap();
+        assert_eq!(Header::read(&mut port).unwrap(), header);
+    }
+
+    #[rstest::rstest]
+    #[case(ZRPOS_HEADER.with_count(1234))]
+    #[case(ZACK_HEADER.with_count(1234))]
+    #[case(ZFIN_HEADER)]
+    pub fn test_read_hex_trailer(#[case] header: Header, #[values(false, true)] escctl: bool) {
+        let mut input = vec![];
+        header
+            .write_escctl(&mut input, escctl, Dialect::default())
+            .unwrap();
+        let mut next = vec![];
+        ZDATA_HEADER.write(&mut next).unwrap();
+        input.extend_from_slice(&next);
+
+        let mut port = &input[..];
+        read_zpad(&mut port, Dialect::default()).unwrap();
+        assert_eq!(Header::read(&mut port).unwrap(), header);
+        assert_eq!(port, next);
+    }
+
+    #[rstest::rstest]
+    #[case("my file.txt")]
+    #[case(" leading and trailing ")]
+    #[case("$(rm -rf ~) `x` 'y' \"z\" ; & | > *")]
+    pub fn test_read_zfile_name(#[case] name: &str) {
+        let mut input = vec![];
+        Header::write_zfile(&mut input, name, 1234).unwrap();
+        let mut port = Port(&input, vec![]);
+        read_zpad(&mut port, Dialect::default()).unwrap();
+        let header = Header::read(&mut port).unwrap();
+        let file = header.read_zfile(&mut port).unwrap().unwrap();
+        assert_eq!(file.info().name, name);
+        assert_eq!(file.info().size, Some(1234));
+    }
+
+    #[rstest::rstest]
+    #[case(2000, 4)]
+    #[case(255, 300)]
+    pub fn test_read_zfile_overlong(#[case] name_len: usize, #[case] metadata_len: usize) {
+        let mut info = vec![b'a'; name_len];
+        info.push(0);
+        info.extend(core::iter::repeat(b'1').take(metadata_len));
+        info.push(0);
+        let mut input = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE)
+            .write(&mut input)
+            .unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &info).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let result = read(&mut port, &mut (None, 0), &mut vec![]);
+        assert_eq!(result, Err(Error::BadFilename));
+        assert!(contains(&port.1, &ABORT));
+        // Reading stopped at the first byte too many:
+        assert!(port.0.len() > 20);
+    }
+
+    #[test]
+    pub fn test_read_zdata_gap() {
+        let len = SUBPACKET_SIZE;
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let (half, ahead) = (len as usize / 2, len as usize * 3 / 4);
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", len).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[..half]).unwrap();
+        // The sender skips ahead, e.g. because it missed a ZRPOS:
+        ZDATA_HEADER
+            .with_count(ahead as u32)
+            .write(&mut input)
+            .unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[ahead..]).unwrap();
+        ZDATA_HEADER
+            .with_count(half as u32)
+            .write(&mut input)
+            .unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[half..]).unwrap();
+        ZEOF_HEADER.with_count(len).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        read(&mut port, &mut (None, 0), &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let mut zrpos = vec![];
+        ZRPOS_HEADER
+            .with_count(half as u32)
+            .write(&mut zrpos)
+            .unwrap();
+        assert!(contains(&port.1, &zrpos));
+    }
+
+    #[test]
+    pub fn test_read_error_offset() {
+        /// Fails writes past the limit.
+        struct Limited(Vec<u8>, usize);
+
+        impl Write for Limited {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0.len() + buf.len() > self.1 {
+                    return Err(std::io::ErrorKind::Other.into());
+                }
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let chunk = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 300).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCG, &chunk).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCG, &chunk).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &chunk).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut state = (None, 0);
+        let mut out = Limited(vec![], 250);
+        assert_eq!(
+            read(&mut port, &mut state, &mut out).map(|_| ()),
+            Err(Error::InvalidData)
+        );
+        assert_eq!(state.1, 200);
+        assert_eq!(out.0.len(), 200);
+    }
+
+    #[rstest::rstest]
+    #[case(Zrinit::CANFC32, Encoding::ZBIN32)]
+    #[case(Zrinit::empty(), Encoding::ZBIN)]
+    pub fn test_write_zrinit_encoding(#[case] zrinit: Zrinit, #[case] encoding: Encoding) {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        write(&mut port, &mut std::io::Cursor::new(&data), "test", None).unwrap();
+
+        let mut zfile = vec![];
+        Header::new(encoding, Frame::ZFILE)
+            .write(&mut zfile)
+            .unwrap();
+        assert!(contains(&port.1, &zfile));
+        let mut zdata = vec![];
+        Header::new(encoding, Frame::ZDATA)
+            .write(&mut zdata)
+            .unwrap();
+        assert!(contains(&port.1, &zdata));
+        let mut subpacket = vec![];
+        write_subpacket(&mut subpacket, encoding, Packet::ZCRCW, &data).unwrap();
+        assert!(contains(&port.1, &subpacket));
+    }
+
+    #[test]
+    pub fn test_write_fixed_subpacket_len() {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            fixed_subpacket_len: true,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write_with(&mut port, &mut file, "test", Some(100), &config).unwrap();
+
+        let mut padded = [0; SUBPACKET_SIZE as usize];
+        padded[..100].copy_from_slice(&data);
+        let mut subpacket = vec![];
+        write_subpacket(&mut subpacket, Encoding::ZBIN32, Packet::ZCRCW, &padded).unwrap();
+        assert!(contains(&port.1, &subpacket));
+    }
+
+    #[rstest::rstest]
+    #[case(true)]
+    #[case(false)]
+    pub fn test_write_zfin(#[case] zrinit_after_zeof: bool) {
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        if zrinit_after_zeof {
+            Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        }
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        write(&mut port, &mut std::io::Cursor::new(&[0; 10]), "test", None).unwrap();
+
+        let mut zfin = vec![];
+        ZFIN_HEADER.write(&mut zfin).unwrap();
+        assert_eq!(port.1.windows(zfin.len()).filter(|w| *w == zfin).count(), 1);
+        zfin.extend_from_slice(b"OO");
+        assert!(port.1.ends_with(&zfin));
+    }
+
+    #[test]
+    pub fn test_file_info() {
+        let info =
+            FileInfo::try_from(&b"foo.txt\x001234 14567123456 100644 0 3 98765\x00"[..]).unwrap();
+        assert_eq!(
+            info,
+            FileInfo {
+                name: "foo.txt".into(),
+                size: Some(1234),
+                mtime: Some(0o14567123456),
+                mode: Some(0o100644),
+                files_remaining: Some(3),
+                bytes_remaining: Some(98765),
+                crc32: None,
+            }
+        );
+
+        let info = FileInfo::try_from(&b"foo.txt\0"[..]).unwrap();
+        assert_eq!(
+            info,
+            FileInfo {
+                name: "foo.txt".into(),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_file_accessors() {
+        let data = b"dir/f\xffo.txt\x001234 14567123456 100644\x00";
+        let file = File {
+            info: FileInfo::try_from(&data[..]).unwrap(),
+        };
+        // The invalid UTF-8 is not replaced:
+        assert_eq!(file.name(), "");
+        assert_eq!(file.size(), Some(1234));
+        assert_eq!(file.mtime(), Some(0o14567123456));
+        assert_eq!(file.mode(), Some(0o100644));
+
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 200).unwrap();
+        let mut port = Port(&input, vec![]);
+        read_zpad(&mut port, Dialect::default()).unwrap();
+        let header = Header::read(&mut port).unwrap();
+        let file = header.read_zfile(&mut port).unwrap().unwrap();
+        assert_eq!(file.name(), "test");
+        assert_eq!(file.size(), Some(200));
+        assert_eq!(file.mtime(), None);
+    }
+
+    #[rstest::rstest]
+    // As sent by older senders:
+    #[case(b"foo.txt\x001234\x00", Ok([Some(1234), None, None]))]
+    #[case(b"foo.txt\x001234 14567123456 100644 \t \x00", Ok([Some(1234), Some(0o14567123456), Some(0o100644)]))]
+    #[case(b"foo.txt\x00 \x00", Ok([None; 3]))]
+    #[case(b"foo.txt\x00abc\x00", Err(InvalidData))]
+    #[case(b"foo.txt\x00-1\x00", Err(InvalidData))]
+    #[case(b"foo.txt\x0099999999999\x00", Err(InvalidData))]
+    // The modification time is octal:
+    #[case(b"foo.txt\x001234 189\x00", Err(InvalidData))]
+    #[case(b"foo.txt\x001234 0 100644x\x00", Err(InvalidData))]
+    #[case(b"foo.txt\x00\xff\x00", Err(InvalidData))]
+    pub fn test_file_info_fields(
+        #[case] data: &[u8],
+        #[case] expected: core::result::Result<[Option<u32>; 3], InvalidData>,
+    ) {
+        let info = FileInfo::try_from(data);
+        assert_eq!(
+            info.map(|info| [info.size, info.mtime, info.mode]),
+            expected
+        );
+    }
+
+    #[test]
+    pub fn test_read_crc_failures() {
+        let data = [b'A'; 100];
+        let mut subpacket = vec![];
+        write_subpacket(&mut subpacket, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+        let mut garbled = subpacket.clone();
+        garbled[50] = b'B';
+
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 100).unwrap();
+        for subpacket in [&garbled, &garbled, &subpacket] {
+            ZDATA_HEADER.write(&mut input).unwrap();
+            input.extend_from_slice(subpacket);
+        }
+        ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let stats = read(&mut port, &mut (None, 0), &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(stats.crc_failures, 2);
+        assert_eq!(stats.retransmits, 2);
+    }
+
+    #[rstest::rstest]
+    #[case(None, &DEFAULT_ATTENTION)]
+    #[case(Some(&b"XY"[..]), b"XY")]
+    pub fn test_read_attention(#[case] zsinit: Option<&[u8]>, #[case] expected: &[u8]) {
+        let data = [0x5a; 200];
+        let mut input = vec![];
+        if let Some(attention) = zsinit {
+            let zsinit = ZsinitParams {
+                flags: Zsinit::empty(),
+                attention: attention.to_vec(),
+            };
+            Header::write_zsinit(
+                &mut input,
+                Encoding::ZBIN32,
+                &zsinit,
+                Dialect::default(),
+                false,
+            )
+            .unwrap();
+        }
+        Header::write_zfile(&mut input, "test", 200).unwrap();
+        // 200 bytes are streamed to a receiver which can take only 128:
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCG, &data[..100]).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[100..]).unwrap();
+        ZDATA_HEADER.with_count(100).write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[100..]).unwrap();
+        ZEOF_HEADER.with_count(200).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            buffer_len: Some(128),
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let stats = read_with(&mut port, &mut (None, 0), &mut out, &config).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(stats.retransmits, 1);
+
+        let mut zrpos = expected.to_vec();
+        ZRPOS_HEADER.with_count(100).write(&mut zrpos).unwrap();
+        assert!(contains(&port.1, &zrpos));
+    }
+
+    #[test]
+    pub fn test_backoff_exponential() {
+        let ms = Duration::from_millis;
+        let mut backoff = Exponential::new(ms(10), ms(100));
+        let delays: Vec<_> = (0..6).map(|n| backoff.next_delay(n)).collect();
+        assert_eq!(delays, [10, 20, 40, 80, 100, 100].map(ms));
+        assert_eq!(backoff.next_delay(u32::MAX), ms(100));
+        assert_eq!(Fixed(ms(10)).next_delay(5), ms(10));
+    }
+
+    #[test]
+    pub fn test_read_backoff() {
+        struct Recorder(Vec<u32>);
+
+        impl Backoff for Recorder {
+            fn next_delay(&mut self, attempt: u32) -> Duration {
+                self.0.push(attempt);
+                Duration::ZERO
+            }
+        }
+
+        // None of the data needs escaping:
+        let data: Vec<u8> = (0..100).map(|i| (i % 32) as u8 + b'@').collect();
+        let mut subpacket = vec![];
+        write_subpacket(&mut subpacket, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+        let mut garbled = subpacket.clone();
+        garbled[50] ^= 0x40;
+
+        // The data is garbled twice in a row, then garbled again after it got
+        // through once:
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 200).unwrap();
+        for (offset, subpacket) in [(0, &garbled), (0, &garbled), (0, &subpacket)] {
+            ZDATA_HEADER.with_count(offset).write(&mut input).unwrap();
+            input.extend_from_slice(subpacket);
+        }
+        for (offset, subpacket) in [(100, &garbled), (100, &subpacket)] {
+            ZDATA_HEADER.with_count(offset).write(&mut input).unwrap();
+            input.extend_from_slice(subpacket);
+        }
+        ZEOF_HEADER.with_count(200).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let recorder = Arc::new(Mutex::new(Recorder(vec![])));
+        let config = Config {
+            backoff: Some(recorder.clone()),
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let stats = read_with(&mut port, &mut (None, 0), &mut out, &config).unwrap();
+        assert_eq!(out, [&data[..], &data[..]].concat());
+        assert_eq!(stats.retransmits, 3);
+        assert_eq!(recorder.lock().unwrap().0, [0, 1, 0]);
+    }
+
+    #[test]
+    pub fn test_back_off_poisoned() {
+        let backoff = Arc::new(Mutex::new(Fixed(Duration::ZERO)));
+        let poisoned = backoff.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoned.lock();
+            panic!("poisons the lock");
+        })
+        .join();
+        assert!(backoff.is_poisoned());
+
+        let config = Config {
+            backoff: Some(backoff),
+            ..Default::default()
+        };
+        let mut attempt = 0;
+        config.back_off(&mut attempt);
+        assert_eq!(attempt, 1);
+    }
+
+    #[test]
+    pub fn test_read_crc_failures_window() {
+        let len = SUBPACKET_SIZE as usize;
+        // None of the data needs escaping:
+        let data: Vec<u8> = (0..3 * len).map(|i| (i % 32) as u8 + b'@').collect();
+        let subpacket = |n: usize, kind| {
+            let mut out = vec![];
+            write_subpacket(&mut out, Encoding::ZBIN32, kind, &data[n * len..][..len]).unwrap();
+            out
+        };
+        let mut garbled = subpacket(1, Packet::ZCRCG);
+        garbled[len / 2] ^= 0x40;
+
+        // The second subpacket of the window is garbled, and then garbled
+        // again when sent from the ZRPOS:
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", data.len() as u32).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        input.extend_from_slice(&subpacket(0, Packet::ZCRCG));
+        input.extend_from_slice(&garbled);
+        input.extend_from_slice(&subpacket(2, Packet::ZCRCW));
+        for subpackets in [
+            [&garbled, &subpacket(2, Packet::ZCRCW)],
+            [&subpacket(1, Packet::ZCRCG), &subpacket(2, Packet::ZCRCW)],
+        ] {
+            ZDATA_HEADER
+                .with_count(len as u32)
+                .write(&mut input)
+                .unwrap();
+            for subpacket in subpackets {
+                input.extend_from_slice(subpacket);
+            }
+        }
+        ZEOF_HEADER
+            .with_count(data.len() as u32)
+            .write(&mut input)
+            .unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let stats = read(&mut port, &mut (None, 0), &mut out).unwrap();
+        assert!(out == data);
+        assert_eq!(stats.crc_failures, 2);
+        assert_eq!(stats.retransmits, 2);
+
+        // Each failure asks for the data from the end of the first subpacket:
+        let mut zrpos = vec![];
+        ZRPOS_HEADER
+            .with_count(len as u32)
+            .write(&mut zrpos)
+            .unwrap();
+        assert_eq!(
+            port.1.windows(zrpos.len()).filter(|w| *w == zrpos).count(),
+            2
+        );
+    }
+
+    /// Flips a bit in the byte written at the given offset.
+    struct Corrupting(std::io::Cursor<Vec<u8>>, usize);
+
+    impl Write for Corrupting {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let start = self.0.get_ref().len();
+            self.0.write_all(buf)?;
+            if let Some(byte) = self.0.get_mut().get_mut(self.1) {
+                if (start..start + buf.len()).contains(&self.1) {
+                    *byte ^= 1;
+                }
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ReadableWriter for Corrupting {
+        fn read_at(&mut self, offset: u32, buf: &mut [u8]) -> Result<u32, InvalidData> {
+            self.0.read_at(offset, buf)
+        }
+    }
+
+    #[rstest::rstest]
+    #[case(None, Ok(()))]
+    #[case(Some(50), Err(Error::InvalidData))]
+    pub fn test_verify(
+        #[case] corrupt: Option<usize>,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        let data: Vec<u8> = (0..100).collect();
+        let input = sender_transcript(&data, data.len() as u32);
+        let mut port = Port(&input, vec![]);
+        let mut out = Corrupting(std::io::Cursor::new(vec![]), corrupt.unwrap_or(usize::MAX));
+        read(&mut port, &mut (None, 0), &mut out).unwrap();
+
+        let crc = CRC32.checksum(&data);
+        assert_eq!(verify(&mut out, data.len() as u32, crc), expected);
+    }
+
+    #[test]
+    pub fn test_send_cancel() {
+        let mut port = vec![];
+        send_cancel(&mut port).unwrap();
+        assert_eq!(port, [[0x18; 10], [0x08; 10]].concat());
+    }
+
+    #[test]
+    pub fn test_read_anonymous() {
+        let data = [0x42; 100];
+        let mut zfile = vec![];
+        Header::write_zfile(&mut zfile, "test", 100).unwrap();
+        let input = sender_transcript(&data, 100);
+
+        let config = Config {
+            accept_anonymous: true,
+            ..Default::default()
+        };
+        let mut port = Port(&input[zfile.len()..], vec![]);
+        let mut out = vec![];
+        let mut state = (None, 0);
+        read_with(&mut port, &mut state, &mut out, &config).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(state.0.unwrap().info(), &FileInfo::default());
+    }
+
+    #[rstest::rstest]
+    #[case(&[ZDLE; 5], Err(Error::Aborted))]
+    #[case(&ABORT, Err(Error::Aborted))]
+    #[case(&[ZDLE; 4], Err(Error::InvalidData))]
+    #[case(&[ZDLE, ZDLE, b'X', ZDLE, ZDLE, ZDLE], Err(Error::InvalidData))]
+    pub fn test_read_abort(
+        #[case] input: &[u8],
+        #[case] expected: core::result::Result<(), Error>,
+        #[values(false, true)] in_data: bool,
+    ) {
+        let mut input = input.to_vec();
+        if in_data {
+            // Aborted halfway through the data:
+            let transcript = sender_transcript(&[0x42; 100], 100);
+            input.splice(..0, transcript[..transcript.len() / 2].iter().copied());
+        }
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let result = read(&mut port, &mut (None, 0), &mut out).map(|_| ());
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    pub fn test_write_abort() {
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        input.extend_from_slice(&ABORT);
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new([0x42; 100]);
+        assert_eq!(
+            write(&mut port, &mut file, "test", Some(100)).map(|_| ()),
+            Err(Error::Aborted)
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(ExistsPolicy::Overwrite, Ok(()), 0, "test")]
+    #[case(ExistsPolicy::Skip, Err(Error::Skipped), 100, "test")]
+    #[case(ExistsPolicy::Resume, Ok(()), 50, "test")]
+    #[case(ExistsPolicy::Rename, Ok(()), 0, "test (2)")]
+    pub fn test_read_exists(
+        #[case] on_exists: ExistsPolicy,
+        #[case] expected: core::result::Result<(), Error>,
+        #[case] offset: u32,
+        #[case] name: &str,
+    ) {
+        let data: Vec<u8> = (0..100).collect();
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 100).unwrap();
+        if offset < 100 {
+            ZDATA_HEADER.with_count(offset).write(&mut input).unwrap();
+            let subpacket = &data[offset as usize..];
+            write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, subpacket).unwrap();
+            ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        }
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        // "test" and "test (1)" exist, the former with 50 bytes:
+        let config = Config {
+            on_exists,
+            exists: Some(Arc::new(|info: &FileInfo| match info.name.as_str() {
+                "test" => Some(50),
+                "test (1)" => Some(10),
+                _ => None,
+            })),
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let mut state = (None, 0);
+        let result = read_with(&mut port, &mut state, &mut out, &config);
+        assert_eq!(result.map(|_| ()), expected);
+        assert_eq!(out, data[offset as usize..]);
+
+        let mut reply = vec![];
+        if expected.is_ok() {
+            ZRPOS_HEADER.with_count(offset).write(&mut reply).unwrap();
+            assert_eq!(state.0.unwrap().info().name, name);
+        } else {
+            ZSKIP_HEADER.write(&mut reply).unwrap();
+        }
+        assert!(contains(&port.1, &reply));
+    }
+
+    #[rstest::rstest]
+    #[case(1500)]
+    #[case(0)]
+    pub fn test_write_zcrc(#[case] len: u32) {
+        let data: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        Header::new(Encoding::ZHEX, Frame::ZCRC)
+            .with_count(len)
+            .write(&mut input)
+            .unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write(&mut port, &mut file, "test", None).unwrap();
+
+        let end = if len == 0 { data.len() } else { len as usize };
+        let mut reply = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZCRC)
+            .with_count(CRC32.checksum(&data[..end]))
+            .write(&mut reply)
+            .unwrap();
+        assert!(contains(&port.1, &reply));
+    }
+
+    #[test]
+    pub fn test_write_zcrc_then_zrpos() {
+        let data: Vec<u8> = (0..2000).map(|i| (i * 7) as u8).collect();
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        // Asked for after ZFILE, to decide whether to skip the file:
+        Header::new(Encoding::ZHEX, Frame::ZCRC)
+            .write(&mut input)
+            .unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(2000).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        let stats = write(&mut port, &mut file, "test", Some(2000)).unwrap();
+        assert_eq!(stats.data_bytes, 2000);
+        assert_eq!(stats.completion, Completion::Clean);
+
+        let position = |needle: &[u8]| port.1.windows(needle.len()).position(|w| w == needle);
+        let mut reply = vec![];
+        Header::new(Encoding::ZHEX, Frame::ZCRC)
+            .with_count(CRC32.checksum(&data))
+            .write(&mut reply)
+            .unwrap();
+        let mut zdata = vec![];
+        ZDATA_HEADER.with_count(0).write(&mut zdata).unwrap();
+        assert!(position(&reply).unwrap() < position(&zdata).unwrap());
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    pub fn test_send_sync() {
+        assert_send_sync::<Config>();
+        assert_send_sync::<Error>();
+        assert_send_sync::<File>();
+        assert_send_sync::<crate::TransferStats>();
+        assert_send_sync::<BufferedSequentialReader<std::fs::File>>();
+        assert_send_sync::<crate::RateLimitedPort<std::fs::File>>();
+        assert_send_sync::<crate::TranscriptPort>();
+    }
+
+    #[rstest::rstest]
+    #[case(0, Err(Error::InvalidData))]
+    #[case(1, Ok(()))]
+    pub fn test_read_empty_reads(
+        #[case] empty_read_retries: u32,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        /// Returns no data on every other read, starting with the first.
+        struct Hiccups<'a>(Port<'a>, bool);
+
+        impl Read for Hiccups<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.1 = !self.1;
+                if self.1 {
+                    return Ok(0);
+                }
+                self.0.read(buf)
+            }
+        }
+
+        impl Write for Hiccups<'_> {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = [0xa5; 100];
+        let input = sender_transcript(&data, 100);
+        let mut port = Hiccups(Port(&input, vec![]), false);
+        let mut out = vec![];
+        let config = Config {
+            empty_read_retries,
+            ..Default::default()
+        };
+        let result = read_with(&mut port, &mut (None, 0), &mut out, &config);
+        assert_eq!(result.map(|_| ()), expected);
+        if expected.is_ok() {
+            assert_eq!(out, data);
+        }
+    }
+
+    #[test]
+    pub fn test_read_seekable_rewind() {
+        let data: Vec<u8> = (0..200).collect();
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 200).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCG, &data[..100]).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[100..]).unwrap();
+        // The sender rewinds on its own, e.g. after a timeout:
+        ZDATA_HEADER.with_count(100).write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[100..]).unwrap();
+        ZEOF_HEADER.with_count(200).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut state = (None, 0);
+        let config = Config {
+            compute_crc: true,
+            ..Default::default()
+        };
+        let stats = read_seekable_with(&mut port, &mut state, &mut out, &config).unwrap();
+        assert_eq!(stats.retransmits, 0);
+        // The data received twice is counted once:
+        assert_eq!(stats.crc32, Some(CRC32.checksum(&data)));
+        assert_eq!(state.1, 200);
+        assert_eq!(out.into_inner(), data);
+    }
+
+    #[rstest::rstest]
+    pub fn test_read_write_buffer_rewind(#[values(0, 64, 150, 4096)] write_buffer: usize) {
+        let data: Vec<u8> = (0..200).collect();
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 200).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCG, &[0xff; 100]).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &[0xff; 100]).unwrap();
+        // What is still collected is overwritten too:
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+        ZEOF_HEADER.with_count(200).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut state = (None, 0);
+        let config = Config {
+            write_buffer,
+            ..Default::default()
+        };
+        read_seekable_with(&mut port, &mut state, &mut out, &config).unwrap();
+        assert_eq!(state.1, 200);
+        assert_eq!(out.into_inner(), data);
+    }
+
+    #[rstest::rstest]
+    #[case(0, &[100; 10])]
+    #[case(400, &[400, 400, 200])]
+    #[case(4096, &[1000])]
+    pub fn test_read_write_buffer(#[case] write_buffer: usize, #[case] expected: &[usize]) {
+        /// Records the length of each write.
+        struct Writes(Vec<u8>, Vec<usize>);
+
+        impl Write for Writes {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.1.push(buf.len());
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 1000).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        for (i, chunk) in data.chunks(100).enumerate() {
+            let packet = if i == 9 { Packet::ZCRCE } else { Packet::ZCRCG };
+            write_subpacket(&mut input, Encoding::ZBIN32, packet, chunk).unwrap();
+        }
+        ZEOF_HEADER.with_count(1000).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = Writes(vec![], vec![]);
+        let mut state = (None, 0);
+        let config = Config {
+            write_buffer,
+            ..Default::default()
+        };
+        read_with(&mut port, &mut state, &mut out, &config).unwrap();
+        assert_eq!(out.0, data);
+        assert_eq!(out.1, expected);
+    }
+
+    #[test]
+    pub fn test_read_zrqinit_restart() {
+        let data: Vec<u8> = (0..200).collect();
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "old", 300).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &[0xff; 150]).unwrap();
+        // The sender restarts with a different file:
+        ZRQINIT_HEADER.write(&mut input).unwrap();
+        input.extend_from_slice(&sender_transcript(&data, 200));
+
+        let mut port = Port(&input, vec![]);
+        let mut out = std::io::Cursor::new(vec![]);
+        let mut state = (None, 0);
+        read_seekable_with(&mut port, &mut state, &mut out, &Config::default()).unwrap();
+        assert_eq!(state.0.unwrap().info().name, "test");
+        assert_eq!(state.1, 200);
+        assert_eq!(&out.into_inner()[..200], data.as_slice());
+
+        let mut zrinit = vec![];
+        Header::write_zrinit(
+            &mut zrinit,
+            Encoding::ZHEX,
+            Zrinit::CANOVIO | Zrinit::CANFC32,
+            Config::default().zrinit_buffer_len(),
+        )
+        .unwrap();
+        let count = port
+            .1
+            .windows(zrinit.len())
+            .filter(|w| *w == zrinit)
+            .count();
+        // Initial, after the restart and after ZEOF:
+        assert_eq!(count, 3);
+
+        // Data already appended cannot be discarded:
+        let mut port = Port(&input, vec![]);
+        let mut state = (None, 0);
+        let result = read(&mut port, &mut state, &mut vec![]);
+        assert_eq!(result, Err(Error::InvalidData));
+    }
+
+    #[rstest::rstest]
+    #[case(true, Ok(()))]
+    #[case(false, Err(Error::InvalidData))]
+    pub fn test_write_eof(
+        #[case] after_zeof: bool,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        if after_zeof {
+            ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        }
+
+        // The receiver closes the port without ZFIN:
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        let result = write(&mut port, &mut file, "test", None);
+        assert_eq!(result.map(|_| ()), expected);
+
+        let mut zeof = vec![];
+        ZEOF_HEADER.with_count(100).write(&mut zeof).unwrap();
+        assert_eq!(contains(&port.1, &zeof), after_zeof);
+    }
+
+    #[test]
+    pub fn test_write_zack_outside_window() {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(1_000_000).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write(&mut port, &mut file, "test", None).unwrap();
+
+        let mut zeof = vec![];
+        ZEOF_HEADER.with_count(1_000_000).write(&mut zeof).unwrap();
+        assert!(!contains(&port.1, &zeof));
+        let mut znak = vec![];
+        ZNAK_HEADER.write(&mut znak).unwrap();
+        assert!(contains(&port.1, &znak));
+    }
+
+    #[rstest::rstest]
+    // A stale ZACK is ignored:
+    #[case(Frame::ZACK, 2, 0)]
+    // Whereas ZRPOS makes the sender resend the first subpacket:
+    #[case(Frame::ZRPOS, 3, 1)]
+    pub fn test_write_behind(
+        #[case] kind: Frame,
+        #[case] subpackets: usize,
+        #[case] retransmits: u64,
+    ) {
+        let data = vec![0x5a; SUBPACKET_SIZE as usize * 2];
+        let mut input = vec![];
+        // Without CANOVIO each subpacket is acknowledged:
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        Header::new(Encoding::ZHEX, kind)
+            .with_count(0)
+            .write(&mut input)
+            .unwrap();
+        for n in 1..=2 {
+            ZACK_HEADER
+                .with_count(SUBPACKET_SIZE * n)
+                .write(&mut input)
+                .unwrap();
+        }
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        let stats = write(&mut port, &mut file, "test", None).unwrap();
+        assert_eq!(stats.retransmits, retransmits);
+        assert_eq!(stats.subpackets_sent, subpackets as u64);
+        // No ZNAK:
+        assert!(!contains(&port.1, b"B06"));
+    }
+
+    #[test]
+    pub fn test_progress() {
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let config = Config {
+            progress: Some(Arc::new({
+                let reports = reports.clone();
+                move |offset| reports.lock().unwrap().push(offset)
+            })),
+            ..Default::default()
+        };
+
+        // Each subpacket is reported, not only the acknowledged ones:
+        let data = vec![0x5a; SUBPACKET_SIZE as usize * 3];
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER
+            .with_count(SUBPACKET_SIZE * 3)
+            .write(&mut input)
+            .unwrap();
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write_with(&mut port, &mut file, "test", None, &config).unwrap();
+        let size = SUBPACKET_SIZE;
+        assert_eq!(*reports.lock().unwrap(), [size, size * 2, size * 3]);
+
+        reports.lock().unwrap().clear();
+        let input = sender_transcript(&data[..100], 100);
+        let mut port = Port(&input, vec![]);
+        read_with(&mut port, &mut (None, 0), &mut vec![], &config).unwrap();
+        assert_eq!(*reports.lock().unwrap(), [100]);
+    }
+
+    /// Port whose reads time out after a delay, and then reach the end.
+    struct Stalling(usize);
+
+    impl Read for Stalling {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            if self.0 == 0 {
+                return Ok(0);
+            }
+            self.0 -= 1;
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            Err(std::io::ErrorKind::TimedOut.into())
+        }
+    }
+
+    impl Write for Stalling {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_heartbeat() {
+        let reports = Arc::new(std::sync::Mutex::new(vec![]));
+        let config = Config {
+            progress: Some(Arc::new({
+                let reports = reports.clone();
+                move |offset| reports.lock().unwrap().push(offset)
+            })),
+            heartbeat: Some(std::time::Duration::from_millis(1)),
+            ..Default::default()
+        };
+        let mut port = Stalling(10);
+        let result = read_with(&mut port, &mut (None, 0), &mut vec![], &config);
+        assert_eq!(result, Err(Error::InvalidData));
+        // Each stall is longer than the heartbeat:
+        assert!(reports.lock().unwrap().len() >= 10);
+    }
+
+    /// Port whose reads time out a few times after every 100 bytes, like a
+    /// channel delivering data in bursts.
+    struct Bursty<'a>(&'a [u8], Vec<u8>, usize);
+
+    impl Read for Bursty<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.2 % 104 >= 100 {
+                self.2 += 1;
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            let n = buf.len().min(100 - self.2 % 104);
+            let n = self.0.read(&mut buf[..n])?;
+            self.2 += n;
+            Ok(n)
+        }
+    }
+
+    impl Write for Bursty<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_read_bursty() {
+        let data: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 3000).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        for (i, chunk) in data.chunks(SUBPACKET_SIZE as usize).enumerate() {
+            let kind = if (i + 1) * (SUBPACKET_SIZE as usize) < data.len() {
+                Packet::ZCRCG
+            } else {
+                Packet::ZCRCW
+            };
+            write_subpacket(&mut input, Encoding::ZBIN32, kind, chunk).unwrap();
+        }
+        ZEOF_HEADER.with_count(3000).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            timeout: Some(std::time::Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let mut port = Bursty(&input, vec![], 0);
+        let mut out = vec![];
+        let stats = read_with(&mut port, &mut (None, 0), &mut out, &config).unwrap();
+        assert!(out == data);
+        assert_eq!(stats.retransmits, 0);
+        assert_eq!(stats.crc_failures, 0);
+        // Only the ZRPOS answering ZFILE, and no ZNAK:
+        assert_eq!(port.1.windows(3).filter(|w| w == b"B09").count(), 1);
+        assert!(!contains(&port.1, b"B06"));
+    }
+
+    #[rstest::rstest]
+    #[case("data-1.bin", false, Ok(()))]
+    #[case("../data.bin", false, Err(Error::Skipped))]
+    #[case("../data.bin", true, Err(Error::Canceled))]
+    pub fn test_read_name_filter(
+        #[case] name: &str,
+        #[case] abort_rejected_name: bool,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zfile(&mut input, name, 100).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+        ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            name_filter: Some(Arc::new(|name: &str| {
+                name.bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+            })),
+            abort_rejected_name,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let result = read_with(&mut port, &mut (None, 0), &mut out, &config);
+        assert_eq!(result.map(|_| ()), expected);
+        assert_eq!(out.len(), if expected.is_ok() { 100 } else { 0 });
+
+        let mut zskip = vec![];
+        ZSKIP_HEADER.write(&mut zskip).unwrap();
+        assert_eq!(contains(&port.1, &zskip), expected == Err(Error::Skipped));
+        assert_eq!(contains(&port.1, &ABORT), abort_rejected_name);
+    }
+
+    #[rstest::rstest]
+    #[case(Zsinit::empty())]
+    #[case(Zsinit::ESCCTL)]
+    pub fn test_read_zsinit_escctl(#[case] flags: Zsinit) {
+        let data = [0x5a; 100];
+        let zsinit = ZsinitParams {
+            flags,
+            attention: vec![],
+        };
+        let mut input = vec![];
+        Header::write_zsinit(
+            &mut input,
+            Encoding::ZBIN32,
+            &zsinit,
+            Dialect::default(),
+            false,
+        )
+        .unwrap();
+        Header::write_zfile(&mut input, "test", 100).unwrap();
+        ZDATA_HEADER.write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+        ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        read(&mut port, &mut (None, 0), &mut out).unwrap();
+        assert_eq!(out, data);
+
+        let escctl = flags.contains(Zsinit::ESCCTL);
+        let mut zack = vec![];
+        ZACK_HEADER
+            .with_count(1)
+            .write_escctl(&mut zack, escctl, Dialect::default())
+            .unwrap();
+        let start = port.1.windows(zack.len()).position(|w| w == zack).unwrap();
+        // Only the ZRINIT before the ZSINIT has the control characters as is:
+        let ctl = port.1[start..]
+            .iter()
+            .any(|b| [b'\r', b'\n', XON].contains(b));
+        assert_eq!(ctl, !escctl);
+    }
+
+    #[test]
+    pub fn test_write_zsinit() {
+        let data = [0x5a; 100];
+        let zsinit_params = ZsinitParams {
+            flags: Zsinit::ESCCTL,
+            attention: b"\x03".to_vec(),
+        };
+        let config = Config {
+            send_zsinit: Some(zsinit_params.clone()),
+            ..Default::default()
+        };
+        let mut zsinit = vec![];
+        Header::write_zsinit(
+            &mut zsinit,
+            Encoding::ZBIN32,
+            &zsinit_params,
+            Dialect::default(),
+            false,
+        )
+        .unwrap();
+        let mut zfile = vec![];
+        Header::write_zfile(&mut zfile, "test", 100).unwrap();
+
+        // ZFILE waits for the ZACK:
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        let result = write_with(&mut port, &mut file, "test", Some(100), &config);
+        assert_eq!(result, Err(Error::InvalidData));
+        assert!(contains(&port.1, &zsinit));
+        assert!(!contains(&port.1, &zfile));
+
+        ZACK_HEADER.with_count(1).write(&mut input).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write_with(&mut port, &mut file, "test", Some(100), &config).unwrap();
+        let zsinit_at = port.1.windows(zsinit.len()).position(|w| w == zsinit);
+        let zfile_at = port.1.windows(zfile.len()).position(|w| w == zfile);
+        assert!(zsinit_at.unwrap() < zfile_at.unwrap());
+
+        // The receiver acknowledges ZSINIT, escaping control characters
+        // as asked:
+        let mut input = zsinit;
+        input.extend_from_slice(&sender_transcript(&data, 100));
+        let mut port = Port(&input, vec![]);
+        read(&mut port, &mut (None, 0), &mut vec![]).unwrap();
+        let mut zack = vec![];
+        ZACK_HEADER
+            .with_count(1)
+            .write_escctl(&mut zack, true, Dialect::default())
+            .unwrap();
+        assert!(contains(&port.1, &zack));
+    }
+
+    #[rstest::rstest]
+    #[case(0, None, 0)]
+    #[case(1, None, 1)]
+    #[case(SUBPACKET_SIZE as u64, None, 1)]
+    #[case(SUBPACKET_SIZE as u64 + 1, None, 2)]
+    #[case(3 * SUBPACKET_SIZE as u64, None, 3)]
+    #[case(3 * SUBPACKET_SIZE as u64 - 1, None, 3)]
+    #[case(256, Some(128), 2)]
+    #[case(257, Some(128), 3)]
+    pub fn test_subpacket_count(
+        #[case] size: u64,
+        #[case] buffer_len: Option<u16>,
+        #[case] expected: u64,
+    ) {
+        let config = Config {
+            buffer_len,
+            ..Default::default()
+        };
+        assert_eq!(subpacket_count(size, &config), Ok(expected));
+    }
+
+    #[test]
+    pub fn test_write_zcompl() {
+        let mut out = vec![];
+        Header::write_zcompl(&mut out, Encoding::ZHEX, 0x0102).unwrap();
+        assert!(out.starts_with(b"**\x18B0f02010000"));
+        read_zpad(&mut &out[..], Dialect::default()).unwrap();
+        let header = Header::read(&mut &out[3..]).unwrap();
+        assert_eq!(header.kind(), Frame::ZCOMPL);
+        assert_eq!(header.count(), 0x0102);
+
+        // A command from the sender is refused:
+        let mut input = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZCOMMAND)
+            .write(&mut input)
+            .unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, b"rm -rf /\0").unwrap();
+        input.extend_from_slice(&sender_transcript(&[0x5a; 100], 100));
+        let mut port = Port(&input, vec![]);
+        read(&mut port, &mut (None, 0), &mut vec![]).unwrap();
+        let mut zcompl = vec![];
+        Header::write_zcompl(&mut zcompl, Encoding::ZHEX, 1).unwrap();
+        assert!(contains(&port.1, &zcompl));
+    }
+
+    #[rstest::rstest]
+    #[case(Zrinit::CANFC32 | Zrinit::CANOVIO, 1)]
+    #[case(Zrinit::CANFC32, 3)]
+    pub fn test_write_canovio(#[case] zrinit: Zrinit, #[case] acks: usize) {
+        let data = vec![0x5a; SUBPACKET_SIZE as usize * 3];
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        for n in 1..=acks {
+            let count = SUBPACKET_SIZE * 3 * n as u32 / acks as u32;
+            ZACK_HEADER.with_count(count).write(&mut input).unwrap();
+        }
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write(&mut port, &mut file, "test", None).unwrap();
+
+        // ZCRCW ends ZFILE and each window:
+        let zcrcw = port.1.windows(2).filter(|w| *w == [ZDLE, b'k']).count();
+        assert_eq!(zcrcw, 1 + acks);
+        let zcrcg = port.1.windows(2).filter(|w| *w == [ZDLE, b'i']).count();
+        assert_eq!(zcrcg, 3 - acks);
+    }
+
+    #[rstest::rstest]
+    #[case(Zrinit::CANFC32 | Zrinit::CANOVIO | Zrinit::CANFDX | Zrinit::ESCCTL, 2048, "CRC32, window=2048, ESCCTL=on, ESC8=off, FDX")]
+    #[case(Zrinit::CANOVIO | Zrinit::ESC8, 0, "CRC16, window=none, ESCCTL=off, ESC8=on, HDX")]
+    pub fn test_write_negotiated(
+        #[case] zrinit: Zrinit,
+        #[case] window: u16,
+        #[case] expected: &str,
+    ) {
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, window).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new([0x5a; 100]);
+        let stats = write(&mut port, &mut file, "test", None).unwrap();
+        let negotiated = stats.negotiated.unwrap();
+        assert_eq!(negotiated.window, window as u32);
+        assert_eq!(negotiated.to_string(), expected);
+    }
+
+    #[test]
+    pub fn test_write_send_crc() {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZSKIP_HEADER.write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            send_crc: true,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        let _ = write_with(&mut port, &mut file, "test", Some(100), &config);
+
+        let mut zfile = vec![];
+        let crc = Some(CRC32.checksum(&data));
+        Header::write_zfile_with(
+            &mut zfile,
+            Encoding::ZBIN32,
+            "test",
+            100,
+            crc,
+            &config,
+            false,
+        )
+        .unwrap();
+        assert!(contains(&port.1, &zfile));
+
+        let mut port = Port(&zfile, vec![]);
+        read_zpad(&mut port, Dialect::default()).unwrap();
+        let header = Header::read(&mut port).unwrap();
+        assert_eq!(header.flags[2], ZMCRC);
+        let file = header.read_zfile(&mut port).unwrap().unwrap();
+        assert_eq!(file.info().crc32, crc);
+    }
+
+    #[rstest::rstest]
+    // The local copy is the same, even if it would be overwritten:
+    #[case(ExistsPolicy::Overwrite, 0, Err(Error::Skipped))]
+    // It differs, even if it would be skipped:
+    #[case(ExistsPolicy::Skip, 1, Ok(()))]
+    pub fn test_read_existing_crc(
+        #[case] on_exists: ExistsPolicy,
+        #[case] local_diff: u32,
+        #[case] expected: core::result::Result<(), Error>,
+    ) {
+        let data = [0x5a; 100];
+        let crc = CRC32.checksum(&data);
+        let mut input = vec![];
+        Header::write_zfile_with(
+            &mut input,
+            Encoding::ZBIN32,
+            "test",
+            100,
+            Some(crc),
+            &Config::default(),
+            false,
+        )
+        .unwrap();
+        // The rest of the session follows a ZFILE without the CRC:
+        let mut plain = vec![];
+        Header::write_zfile(&mut plain, "test", 100).unwrap();
+        input.extend_from_slice(&sender_transcript(&data, 100)[plain.len()..]);
+
+        let config = Config {
+            on_exists,
+            exists: Some(Arc::new(|_: &FileInfo| Some(100))),
+            existing_crc: Some(Arc::new(move |_: &FileInfo| Some(crc ^ local_diff))),
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let result = read_with(&mut port, &mut (None, 0), &mut out, &config);
+        assert_eq!(result.map(|_| ()), expected);
+        assert_eq!(out.is_empty(), expected.is_err());
+    }
+
+    #[test]
+    pub fn test_write_batch_sentinel() {
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        // After the file, the sentinel is received, and then the session ends:
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            batch_sentinel: Some("END".to_string()),
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new([0x5a; 100]);
+        write_with(&mut port, &mut file, "test", Some(100), &config).unwrap();
+
+        let find = |needle: &[u8]| port.1.windows(needle.len()).position(|w| w == needle);
+        let mut file = vec![];
+        Header::write_zfile(&mut file, "test", 100).unwrap();
+        let mut sentinel = vec![];
+        Header::write_zfile(&mut sentinel, "END", 0).unwrap();
+        let mut zeof = vec![];
+        ZEOF_HEADER.with_count(0).write(&mut zeof).unwrap();
+        let mut zfin = vec![];
+        ZFIN_HEADER.write(&mut zfin).unwrap();
+        let file = find(&file).unwrap();
+        let sentinel = find(&sentinel).unwrap();
+        let zfin = find(&zfin).unwrap();
+        assert!(file < sentinel && sentinel < zfin);
+        assert!(port.1[sentinel..zfin]
+            .windows(zeof.len())
+            .any(|w| w == zeof));
+    }
+
+    #[rstest::rstest]
+    #[case(None, Ok(3))]
+    #[case(Some(3), Ok(3))]
+    #[case(Some(2), Err(Error::TooManyFiles))]
+    pub fn test_read_max_files(
+        #[case] max_files: Option<u32>,
+        #[case] expected: core::result::Result<usize, Error>,
+    ) {
+        let data = [0x42; 100];
+        let mut input = vec![];
+        for name in ["a", "b", "c"] {
+            Header::write_zfile(&mut input, name, 100).unwrap();
+            ZDATA_HEADER.write(&mut input).unwrap();
+            write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+            ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        }
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            max_files,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        let mut names = vec![];
+        let accept = |file: &File| {
+            names.push(file.name().to_string());
+            Some(vec![])
+        };
+        let result = read_batch(&mut port, accept, &config);
+        assert_eq!(result.map(|files| files.len()), expected);
+        // The files up to the limit are received in full:
+        let count = expected.unwrap_or(2);
+        assert_eq!(names, ["a", "b", "c"][..count]);
+        assert_eq!(port.1.ends_with(&ABORT), expected.is_err());
+    }
+
+    #[rstest::rstest]
+    #[case(false, Err(Error::InvalidData))]
+    #[case(true, Ok(()))]
+    pub fn test_read_batch_file_error(
+        #[case] continue_on_file_error: bool,
+        #[case] expected: core::result::Result<(), Error>,
+        #[values(0, 4096)] write_buffer: usize,
+    ) {
+        /// Fails writes past the limit.
+        #[derive(Debug, PartialEq)]
+        struct Limited(Vec<u8>, usize);
+
+        impl Write for Limited {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.0.len() + buf.len() > self.1 {
+                    return Err(std::io::ErrorKind::Other.into());
+                }
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let data = [0x42; 100];
+        let mut input = vec![];
+        for name in ["a", "b", "c"] {
+            Header::write_zfile(&mut input, name, 100).unwrap();
+            ZDATA_HEADER.write(&mut input).unwrap();
+            write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data).unwrap();
+            ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        }
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let config = Config {
+            continue_on_file_error,
+            write_buffer,
+            ..Default::default()
+        };
+        let mut port = Port(&input, vec![]);
+        // The disk fills up while receiving "b":
+        let accept = |file: &File| match file.name() {
+            "b" => Some(Limited(vec![], 50)),
+            _ => Some(Limited(vec![], usize::MAX)),
+        };
+        let result = read_batch(&mut port, accept, &config);
+        assert_eq!(result.as_ref().map(|_| ()).map_err(|err| *err), expected);
+        let mut zskip = vec![];
+        ZSKIP_HEADER.write(&mut zskip).unwrap();
+        assert_eq!(contains(&port.1, &zskip), continue_on_file_error);
+        if let Ok(files) = result {
+            let names: Vec<_> = files.iter().map(|(file, _)| file.name()).collect();
+            assert_eq!(names, ["a", "b", "c"]);
+            let received = FileOutcome::Received(Limited(data.to_vec(), usize::MAX));
+            assert_eq!(files[0].1, received);
+            assert!(matches!(files[1].1, FileOutcome::Failed(_)));
+            assert_eq!(files[2].1, received);
+        }
+    }
+
+    #[test]
+    pub fn test_write_batch() {
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        // The second file is refused:
+        ZSKIP_HEADER.write(&mut input).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(50).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut a = std::io::Cursor::new(vec![0x11; 100]);
+        let mut b = std::io::Cursor::new(vec![0x22; 100]);
+        let mut c = std::io::Cursor::new(vec![0x33; 50]);
+        let files = [
+            ("a", Some(100), &mut a),
+            ("b", Some(100), &mut b),
+            ("c", Some(50), &mut c),
+        ];
+        let mut port = Port(&input, vec![]);
+        let stats = write_batch(&mut port, files, &Config::default()).unwrap();
+        assert_eq!(stats.data_bytes, 150);
+        assert_eq!(stats.peer_received_bytes, Some(150));
+        assert_eq!(stats.completion, Completion::Clean);
+
+        let find = |needle: &[u8]| port.1.windows(needle.len()).position(|w| w == needle);
+        let zfile = |name, size| {
+            let mut zfile = vec![];
+            Header::write_zfile(&mut zfile, name, size).unwrap();
+            find(&zfile).unwrap()
+        };
+        let mut zfin = vec![];
+        ZFIN_HEADER.write(&mut zfin).unwrap();
+        assert!(zfile("a", 100) < zfile("b", 100));
+        assert!(zfile("b", 100) < zfile("c", 50));
+        assert!(zfile("c", 50) < find(&zfin).unwrap());
+        // Only after the last file:
+        assert_eq!(port.1.windows(zfin.len()).filter(|w| *w == zfin).count(), 1);
+        assert!(!contains(&port.1, &[0x22; 16]));
+    }
+
+    #[test]
+    pub fn test_write_batch_skip_during_data() {
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        // The window stops the sender after about half of the first file:
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 1000).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZSKIP_HEADER.write(&mut input).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut input).unwrap();
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 1000).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut a = std::io::Cursor::new([[0x11; 1000], [0x12; 1000]].concat());
+        let mut b = std::io::Cursor::new(vec![0x33; 100]);
+        let files = [("a", Some(2000), &mut a), ("b", Some(100), &mut b)];
+        let mut port = Port(&input, vec![]);
+        let stats = write_batch(&mut port, files, &Config::default()).unwrap();
+
+        let len = SUBPACKET_SIZE.min(1000);
+        let half = (1000 / len * len) as u64;
+        assert_eq!(stats.data_bytes, half + 100);
+        assert_eq!(stats.peer_received_bytes, Some(100));
+        assert!(!contains(&port.1, &[0x12; 16]));
+        let mut zfile = vec![];
+        Header::write_zfile(&mut zfile, "b", 100).unwrap();
+        assert!(contains(&port.1, &zfile));
+        assert!(contains(&port.1, &[0x33; 100]));
+    }
+
+    #[test]
+    pub fn test_write_batch_empty() {
+        let mut input = vec![];
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let config = Config::default();
+        let stats = write_batch::<_, std::io::Cursor<Vec<u8>>, _>(&mut port, [], &config).unwrap();
+        assert_eq!(stats.completion, Completion::Clean);
+        let mut zfin = vec![];
+        ZFIN_HEADER.write(&mut zfin).unwrap();
+        assert!(contains(&port.1, &zfin));
+    }
+
+    #[test]
+    pub fn test_write_with_buffers() {
+        let data: Vec<u8> = (0..SUBPACKET_SIZE * 3 + 100).map(|i| i as u8).collect();
+        let mut input = vec![];
+        Header::write_zrinit(
+            &mut input,
+            Encoding::ZHEX,
+            Zrinit::CANFC32 | Zrinit::CANOVIO,
+            0,
+        )
+        .unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        ZACK_HEADER
+            .with_count(data.len() as u32)
+            .write(&mut input)
+            .unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let expected = write(&mut port, &mut std::io::Cursor::new(&data), "test", None).unwrap();
+
+        // The buffers are reused:
+        let mut buffers = Buffers::new();
+        for _ in 0..2 {
+            let mut reused = Port(&input, vec![]);
+            let mut file = std::io::Cursor::new(&data);
+            let config = Config::default();
+            let stats =
+                write_with_buffers(&mut reused, &mut file, "test", None, &config, &mut buffers)
+                    .unwrap();
+            assert_eq!(stats, expected);
+            assert!(reused.1 == port.1);
+        }
+    }
+
+    #[test]
+    pub fn test_read_encrypted() {
+        let data = [0x5a; 100];
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "test", 100).unwrap();
+        // Sets ZTCRYPT in ZF2:
+        let mut encrypted = vec![];
+        Header::new(Encoding::ZBIN32, Frame::ZFILE)
+            .with_flags(&[0, ZTCRYPT, 0, 0])
+            .write(&mut encrypted)
+            .unwrap();
+        let zfile_len = encrypted.len();
+        encrypted.extend_from_slice(&input[zfile_len..]);
+        encrypted.extend_from_slice(&sender_transcript(&data, 100)[input.len()..]);
+
+        let mut port = Port(&encrypted, vec![]);
+        let mut out = vec![];
+        let result = read(&mut port, &mut (None, 0), &mut out);
+        assert_eq!(result, Err(Error::EncryptionUnsupported));
+        assert!(out.is_empty());
+        assert!(port.1.ends_with(&ABORT));
+
+        // CANCRY is not advertised:
+        let mut zrinit = &port.1[..];
+        read_zpad(&mut zrinit, Dialect::default()).unwrap();
+        let header = Header::read(&mut zrinit).unwrap();
+        assert_eq!(header.kind(), Frame::ZRINIT);
+        assert!(!Zrinit::from_bits_truncate(header.flags[3]).contains(Zrinit::CANCRY));
+    }
+
+    #[test]
+    pub fn test_write_legacy_mode() {
+        let data = vec![0x5a; SUBPACKET_SIZE as usize * 2];
+        let config = Config {
+            legacy_mode: true,
+            double_pad: true,
+            zfile_packet: Packet::ZCRCG,
+            ..Default::default()
+        };
+        // A minimal receiver, which offers more than it needs to:
+        let mut input = vec![];
+        let zrinit = Zrinit::CANFC32 | Zrinit::CANOVIO;
+        Header::write_zrinit(&mut input, Encoding::ZHEX, zrinit, 0).unwrap();
+        // Garbled:
+        input.extend_from_slice(&[ZPAD, ZDLE, Encoding::ZBIN as u8, 0xff, 0, 0, 0, 0, 0, 0]);
+        ZRPOS_HEADER.with_count(0).write(&mut input).unwrap();
+        for n in 1..=2 {
+            ZACK_HEADER
+                .with_count(SUBPACKET_SIZE * n)
+                .write(&mut input)
+                .unwrap();
+        }
+        Header::write_zrinit(&mut input, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        let mut port = Port(&input, vec![]);
+        let mut file = std::io::Cursor::new(&data);
+        write_with(
+            &mut port,
+            &mut file,
+            "test",
+            Some(data.len() as u32),
+            &config,
+        )
+        .unwrap();
+
+        let mut zfile = vec![];
+        Header::write_zfile_with(
+            &mut zfile,
+            Encoding::ZBIN,
+            "test",
+            data.len() as u32,
+            None,
+            &Config::default(),
+            false,
+        )
+        .unwrap();
+        assert!(contains(&port.1, &zfile));
+        assert!(!contains(&port.1, &[ZPAD, ZDLE, Encoding::ZBIN32 as u8]));
+        assert!(!contains(
+            &port.1,
+            &[ZPAD, ZPAD, ZDLE, Encoding::ZBIN as u8]
+        ));
+        assert!(!contains(&port.1, &[ZDLE, Packet::ZCRCG as u8]));
+        let mut znak = vec![];
+        ZNAK_HEADER.write(&mut znak).unwrap();
+        assert!(!contains(&port.1, &znak));
+    }
+
+    #[test]
+    pub fn test_read_resume_complete() {
+        use std::collections::HashMap;
+
+        // Files received in an earlier, interrupted session:
+        let received = HashMap::from([("a".to_string(), 100), ("b".to_string(), 40)]);
+        let config = Config {
+            on_exists: ExistsPolicy::Resume,
+            exists: Some(Arc::new(move |info: &FileInfo| {
+                received.get(&info.name).copied()
+            })),
+            ..Default::default()
+        };
+        let data: Vec<u8> = (0..100).collect();
+
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "a", 100).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        let result = read_with(&mut port, &mut (None, 0), &mut out, &config);
+        assert_eq!(result, Err(Error::Skipped));
+        assert!(out.is_empty());
+
+        let mut input = vec![];
+        Header::write_zfile(&mut input, "b", 100).unwrap();
+        ZDATA_HEADER.with_count(40).write(&mut input).unwrap();
+        write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, &data[40..]).unwrap();
+        ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        ZFIN_HEADER.write(&mut input).unwrap();
+        let mut port = Port(&input, vec![]);
+        let mut out = vec![];
+        read_with(&mut port, &mut (None, 0), &mut out, &config).unwrap();
+        assert_eq!(out, data[40..]);
+        let mut zrpos = vec![];
+        ZRPOS_HEADER.with_count(40).write(&mut zrpos).unwrap();
+        assert!(contains(&port.1, &zrpos));
+    }
+
+    #[rstest::rstest]
+    #[case(40, Ok(100))]
+    #[case(100, Err(Error::Skipped))]
+    #[case(150, Err(Error::Skipped))]
+    pub fn test_read_resume_offset(
+        #[case] received: u32,
+        #[case] expected: core::result::Result<u32, Error>,
+    ) {
+        let data: Vec<u8> = (0..100).collect();
+        let mut input = vec![];
+        ZRQINIT_HEADER.write(&mut input).unwrap();
+        Header::write_zfile(&mut input, "test", 100).unwrap();
+        if expected.is_ok() {
+            ZDATA_HEADER.with_count(received).write(&mut input).unwrap();
+            let rest = &data[received as usize..];
+            write_subpacket(&mut input, Encoding::ZBIN32, Packet::ZCRCW, rest).unwrap();
+            ZEOF_HEADER.with_count(100).write(&mut input).unwrap();
+        }
+        ZFIN_HEADER.write(&mut input).unwrap();
+
+        // The first bytes were received in an earlier session:
+        let mut out = data[..40.min(received as usize)].to_vec();
+        let mut state = (None, received);
+        let mut port = Port(&input, vec![]);
+        let result = read(&mut port, &mut state, &mut out);
+        assert_eq!(result.map(|_| state.1), expected);
+        let mut zrpos = vec![];
+        ZRPOS_HEADER.with_count(received).write(&mut zrpos).unwrap();
+        assert_eq!(contains(&port.1, &zrpos), expected.is_ok());
+        if expected.is_ok() {
+            assert_eq!(out, data);
+        }
+    }
+
+    #[rstest::rstest]
+    #[case(Encoding::ZBIN, Packet::ZCRCE)]
+    #[case(Encoding::ZBIN, Packet::ZCRCG)]
+    #[case(Encoding::ZBIN32, Packet::ZCRCQ)]
+    #[case(Encoding::ZBIN32, Packet::ZCRCW)]
+    pub fn test_parse_subpacket(#[case] encoding: Encoding, #[case] kind: Packet) {
+        let data = [ZDLE, ZPAD, XON, 0x00, 0xff];
+        let mut subpacket = vec![];
+        write_subpacket(&mut subpacket, encoding, kind, &data).unwrap();
+        let len = subpacket.len();
+        // Followed by the next header:
+        ZACK_HEADER.write(&mut subpacket).unwrap();
+
+        assert_eq!(
+            parse_subpacket(&subpacket, encoding),
+            Ok((kind, data.to_vec(), len))
+        );
+        assert_eq!(
+            parse_subpacket(&subpacket[..len - 1], encoding),
+            Err(InvalidData)
+        );
+    }
+
+    #[rstest::rstest]
+    #[case(Packet::ZCRCW)]
+    #[case(Packet::ZCRCQ)]
+    #[case(Packet::ZCRCG)]
+    pub fn test_zfile_packet(#[case] zfile_packet: Packet) {
+        let config = Config {
+            zfile_packet,
+            ..Default::default()
+        };
+        let mut zfile = vec![];
+        Header::write_zfile_with(
+            &mut zfile,
+            Encoding::ZBIN32,
+            "test",
+            100,
+            None,
+            &config,
+            false,
+        )
+        .unwrap();
+        let mut subpacket = vec![];
+        write_subpacket(
+            &mut subpacket,
+            Encoding::ZBIN32,
+            zfile_packet,
+            b"test\x00100\x00",
+        )
+        .unwrap();
+        assert!(zfile.ends_with(&subpacket));
+
+        let mut port = Port(&zfile[zfile.len() - subpacket.len()..], vec![]);
+        let header = Header::new(Encoding::ZBIN32, Frame::ZFILE);
+        let file = header.read_zfile(&mut port).unwrap().unwrap();
+        assert_eq!(file.info().name, "test");
+        assert_eq!(file.info().size, Some(100));
+        let mut zrpos = vec![];
+        ZRPOS_HEADER.with_count(0).write(&mut zrpos).unwrap();
+        assert_eq!(port.1, zrpos);
+    }
+
+    #[rstest::rstest]
+    #[case(false, 1)]
+    #[case(true, 0)]
+    pub fn test_read_unknown_encoding(#[case] skip_unknown_encoding: bool, #[case] naks: u64) {
+        let header = [ZPAD, ZDLE, 0x44, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(Header::read(&mut &header[2..]), Err(InvalidData));
+
+        let data = [0x5a; 100];
+        let mut input = header.to_vec();
+        input.extend_from_slice(&sender_transcript(&data, 100));
+        let mut port = Port(&input, vec![]);
+        let config = Config {
+            skip_unknown_encoding,
+            ..Default::default()
+        };
+        let mut out = vec![];
+        let stats = read_with(&mut port, &mut (None, 0), &mut out, &config).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(stats.crc_failures, naks);
+        let mut znak = vec![];
+        ZNAK_HEADER.write(&mut znak).unwrap();
+        assert_eq!(contains(&port.1, &znak), naks > 0);
+    }
+
+    #[test]
+    pub fn test_read_subpacket_overflow() {
+        let input = vec![0x5a; RX_BUFFER_SIZE + 1];
+        let mut rx_buf = RxBuffer::new();
+        assert_eq!(
+            read_subpacket(
+                &mut input.as_slice(),
+                Encoding::ZBIN32,
+                &mut rx_buf,
+                Dialect::default()
+            ),
+            Err(InvalidData)
+        );
+    }
+
+    #[test]
+    pub fn test_write_zfile_long_name() {
+        let name = "x".repeat(256);
+        let mut out = vec![];
+        assert_eq!(Header::write_zfile(&mut out, &name, 0), Err(InvalidData));
+        assert_eq!(
+            write_subpacket(&mut out, Encoding::ZHEX, Packet::ZCRCW, b"test"),
+            Err(InvalidData)
+        );
+    }
+
+    /// Feeds random bytes and corrupted transcripts to the parsers. Any
+    /// outcome is fine except a panic or a hang.
+    #[test]
+    pub fn test_fuzz() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let data = [0x5a; 100];
+        let mut transcript = vec![];
+        Header::write_zrinit(&mut transcript, Encoding::ZHEX, Zrinit::CANFC32, 0).unwrap();
+        ZRPOS_HEADER.with_count(0).write(&mut transcript).unwrap();
+        ZACK_HEADER.with_count(100).write(&mut transcript).unwrap();
+        ZFIN_HEADER.write(&mut transcript).unwrap();
+        let transcripts = [sender_transcript(&data, 100), transcript];
+
+        for i in 0..2000 {
+            let input: Vec<u8> = if i % 2 == 0 {
+                let len = rng.gen_range(0, 512);
+                rng.gen_iter().take(len).collect()
+            } else {
+                let mut input = transcripts[i / 2 % 2].clone();
+                for _ in 0..rng.gen_range(1, 8) {
+                    let at = rng.gen_range(0, input.len());
+                    input[at] = rng.gen();
+                }
+                let len = rng.gen_range(0, input.len() + 1);
+                input.truncate(len);
+                input
+            };
+
+            let _ = Header::read(&mut input.as_slice());
+            let _ = FileInfo::try_from(input.as_slice());
+            for encoding in [Encoding::ZBIN, Encoding::ZBIN32, Encoding::ZHEX] {
+                let mut rx_buf = RxBuffer::new();
+                let _ = read_subpacket(
+                    &mut input.as_slice(),
+                    encoding,
+                    &mut rx_buf,
+                    Dialect::default(),
+                );
+            }
+            let header = Header::new(Encoding::ZBIN32, Frame::ZFILE);
+            let _ = header.read_zfile(&mut Port(&input, vec![]));
+
+            let mut state = (None, 0);
+            let _ = read(&mut Port(&input, vec![]), &mut state, &mut vec![]);
+            let mut file = std::io::Cursor::new(&data);
+            let _ = write(&mut Port(&input, vec![]), &mut file, "test", None);
+        }
+    }
 }
@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Simulated lossy links
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Properties of a `LossyLink`, which apply to each direction separately.
+/// Probabilities are per byte.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkOptions {
+    /// Probability of a byte being lost
+    pub drop: f64,
+    /// Probability of a byte having one of its bits flipped
+    pub flip: f64,
+    /// Delay after which a byte written can be read at the other end
+    pub latency: Duration,
+    /// Time after which a read with no data fails with
+    /// `io::ErrorKind::TimedOut`. Reads wait forever without it.
+    pub timeout: Option<Duration>,
+    /// Seed of the pseudo-random losses, so that a run can be repeated
+    pub seed: u64,
+}
+
+/// Bytes in flight in one direction
+#[derive(Default)]
+struct Channel {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    bytes: VecDeque<(Instant, u8)>,
+    closed: bool,
+}
+
+/// One end of an in-memory link, which drops and corrupts bytes, and delays
+/// them, as configured in `LinkOptions`. The ends can be used from different
+/// threads. Once an end is dropped, the other one reads the remaining bytes
+/// and then the end of the input, and its writes are lost.
+pub struct LossyLink {
+    rx: Arc<Channel>,
+    tx: Arc<Channel>,
+    options: LinkOptions,
+    rng: u64,
+}
+
+impl LossyLink {
+    /// Returns the two ends of a new link.
+    pub fn pair(options: LinkOptions) -> (LossyLink, LossyLink) {
+        let a = Arc::new(Channel::default());
+        let b = Arc::new(Channel::default());
+        (
+            LossyLink {
+                rx: a.clone(),
+                tx: b.clone(),
+                options,
+                rng: options.seed,
+            },
+            LossyLink {
+                rx: b,
+                tx: a,
+                options,
+                rng: !options.seed,
+            },
+        )
+    }
+
+    /// Returns a uniformly distributed number in `0.0..1.0` (SplitMix64).
+    fn random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Read for LossyLink {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let deadline = self.options.timeout.map(|timeout| Instant::now() + timeout);
+        let mut queue = self.rx.queue.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let due = queue.bytes.iter().take_while(|(at, _)| *at <= now).count();
+            if due > 0 {
+                let count = due.min(buf.len());
+                for (b, (_, byte)) in buf.iter_mut().zip(queue.bytes.drain(..count)) {
+                    *b = byte;
+                }
+                return Ok(count);
+            }
+            if queue.bytes.is_empty() && queue.closed {
+                return Ok(0);
+            }
+            let wake = match (queue.bytes.front(), deadline) {
+                (Some((at, _)), Some(deadline)) => (*at).min(deadline),
+                (Some((at, _)), None) => *at,
+                (None, Some(deadline)) => deadline,
+                (None, None) => {
+                    queue = self.rx.ready.wait(queue).unwrap();
+                    continue;
+                }
+            };
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            queue = self.rx.ready.wait_timeout(queue, wake - now).unwrap().0;
+        }
+    }
+}
+
+impl Write for LossyLink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let at = Instant::now() + self.options.latency;
+        let mut bytes = Vec::with_capacity(buf.len());
+        for byte in buf {
+            if self.random() < self.options.drop {
+                continue;
+            }
+            let mut byte = *byte;
+            if self.random() < self.options.flip {
+                byte ^= 1 << (self.random() * 8.0) as u32;
+            }
+            bytes.push((at, byte));
+        }
+        let mut queue = self.tx.queue.lock().unwrap();
+        if !queue.closed {
+            queue.bytes.extend(bytes);
+            self.tx.ready.notify_all();
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LossyLink {
+    fn drop(&mut self) {
+        for channel in [&self.rx, &self.tx] {
+            if let Ok(mut queue) = channel.queue.lock() {
+                queue.closed = true;
+            }
+            channel.ready.notify_all();
+        }
+    }
+}
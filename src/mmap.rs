@@ -0,0 +1,58 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Memory-mapped file source
+
+use crate::{InvalidData, Reader};
+use memmap2::Mmap;
+
+/// A `Reader` over a memory-mapped file. Subpackets are copied straight from
+/// the mapping, which avoids a syscall per read when sending large files.
+pub struct MmapReader {
+    map: Mmap,
+    pos: usize,
+}
+
+impl MmapReader {
+    /// Maps `file` into memory.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this or any other
+    /// process, while the reader is alive. Reading a page which is no longer
+    /// backed by the file is undefined behavior, e.g. a `SIGBUS`, and data
+    /// modified in place can be read torn.
+    pub unsafe fn new(file: &std::fs::File) -> std::io::Result<Self> {
+        // SAFETY: passed on to the caller.
+        let map = unsafe { Mmap::map(file)? };
+        if u32::try_from(map.len()).is_err() {
+            return Err(std::io::ErrorKind::InvalidInput.into());
+        }
+        Ok(Self { map, pos: 0 })
+    }
+
+    /// Returns the length of the mapped file.
+    pub fn len(&self) -> u32 {
+        self.map.len() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl Reader for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, InvalidData> {
+        let src = &self.map[self.pos..];
+        let count = src.len().min(buf.len());
+        buf[..count].copy_from_slice(&src[..count]);
+        self.pos += count;
+        Ok(count as u32)
+    }
+
+    fn seek(&mut self, offset: u32) -> Result<u32, InvalidData> {
+        if offset as usize > self.map.len() {
+            return Err(InvalidData);
+        }
+        self.pos = offset as usize;
+        Ok(offset)
+    }
+}
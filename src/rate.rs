@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Bandwidth limiting
+
+use std::io::{self, Read, Write};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// A port which limits the average throughput of the inner port to the given
+/// number of bits per second. Each direction is limited separately by
+/// sleeping whenever the transfer gets ahead of the schedule.
+pub struct RateLimitedPort<T> {
+    inner: T,
+    bits_per_second: u64,
+    start: Option<Instant>,
+    read_bytes: u64,
+    written_bytes: u64,
+}
+
+impl<T> RateLimitedPort<T> {
+    /// # Panics
+    ///
+    /// Panics if `bits_per_second` is zero.
+    pub fn new(inner: T, bits_per_second: u64) -> Self {
+        assert!(bits_per_second > 0);
+        Self {
+            inner,
+            bits_per_second,
+            start: None,
+            read_bytes: 0,
+            written_bytes: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn throttle(start: Instant, bytes: u64, bits_per_second: u64) {
+        let due = Duration::from_secs_f64((bytes * 8) as f64 / bits_per_second as f64);
+        let elapsed = start.elapsed();
+        if due > elapsed {
+            sleep(due - elapsed);
+        }
+    }
+}
+
+impl<T> Read for RateLimitedPort<T>
+where
+    T: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let count = self.inner.read(buf)?;
+        self.read_bytes += count as u64;
+        Self::throttle(start, self.read_bytes, self.bits_per_second);
+        Ok(count)
+    }
+}
+
+impl<T> Write for RateLimitedPort<T>
+where
+    T: Write,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let count = self.inner.write(buf)?;
+        self.written_bytes += count as u64;
+        Self::throttle(start, self.written_bytes, self.bits_per_second);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
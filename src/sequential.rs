@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Sending from non-seekable streams
+
+use crate::{InvalidData, Reader};
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::mpsc::Receiver;
+
+/// A `Reader` over a stream which cannot seek, such as a pipe. The most
+/// recent `window` bytes are kept, so that a `ZRPOS` rewinding within them
+/// can be served. Seeking further back fails.
+pub struct BufferedSequentialReader<R> {
+    inner: R,
+    history: VecDeque<u8>,
+    window: usize,
+    /// Number of bytes read from `inner`
+    end: u32,
+    pos: u32,
+}
+
+impl<R> BufferedSequentialReader<R>
+where
+    R: Read,
+{
+    pub fn new(inner: R, window: usize) -> Self {
+        Self {
+            inner,
+            history: VecDeque::with_capacity(window),
+            window,
+            end: 0,
+            pos: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) -> Result<u32, InvalidData> {
+        let count = self.inner.read(buf).or(Err(InvalidData))?;
+        self.history.extend(&buf[..count]);
+        let excess = self.history.len().saturating_sub(self.window);
+        self.history.drain(..excess);
+        self.end += count as u32;
+        Ok(count as u32)
+    }
+}
+
+impl<R> Reader for BufferedSequentialReader<R>
+where
+    R: Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, InvalidData> {
+        if self.pos == self.end {
+            let count = self.fill(buf)?;
+            self.pos = self.end;
+            return Ok(count);
+        }
+        let behind = (self.end - self.pos) as usize;
+        let start = self.history.len() - behind;
+        let count = behind.min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.history.range(start..start + count)) {
+            *dst = *src;
+        }
+        self.pos += count as u32;
+        Ok(count as u32)
+    }
+
+    fn seek(&mut self, offset: u32) -> Result<u32, InvalidData> {
+        let mut scratch = [0; 256];
+        while self.end < offset {
+            let len = scratch.len().min((offset - self.end) as usize);
+            if self.fill(&mut scratch[..len])? == 0 {
+                return Err(InvalidData);
+            }
+        }
+        if (self.end - offset) as usize > self.history.len() {
+            log::error!("Seek to {} is outside of the buffered window", offset);
+            return Err(InvalidData);
+        }
+        self.pos = offset;
+        Ok(offset)
+    }
+}
+
+/// A `Reader` over the chunks sent to a channel, e.g. by a thread
+/// producing the data while it is sent. Reads block until the next chunk,
+/// and the end of the file is when all senders are dropped. Like
+/// `BufferedSequentialReader`, it can only seek back within the last
+/// `window` bytes.
+pub struct ChannelReader(BufferedSequentialReader<Chunks>);
+
+impl ChannelReader {
+    pub fn new(receiver: Receiver<Vec<u8>>, window: usize) -> Self {
+        let chunks = Chunks {
+            receiver,
+            chunk: Vec::new(),
+            pos: 0,
+        };
+        Self(BufferedSequentialReader::new(chunks, window))
+    }
+}
+
+impl Reader for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<u32, InvalidData> {
+        self.0.read(buf)
+    }
+
+    fn seek(&mut self, offset: u32) -> Result<u32, InvalidData> {
+        self.0.seek(offset)
+    }
+}
+
+struct Chunks {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => (self.chunk, self.pos) = (chunk, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.chunk.len() - self.pos);
+        buf[..count].copy_from_slice(&self.chunk[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
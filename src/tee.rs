@@ -0,0 +1,26 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Receiving into several outputs at once
+
+use crate::{InvalidData, Writer};
+
+/// A `Writer` which writes the data to each of several writers in turn,
+/// e.g. to save a file while processing it as it arrives. The transfer
+/// fails as soon as one of them does. Each write goes to all of them in
+/// full, and so they should block rather than fail with `WouldBlock`.
+pub struct MultiWriter<'a> {
+    writers: Vec<&'a mut dyn Writer>,
+}
+
+impl<'a> MultiWriter<'a> {
+    pub fn new(writers: Vec<&'a mut dyn Writer>) -> Self {
+        Self { writers }
+    }
+}
+
+impl Writer for MultiWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<(), InvalidData> {
+        self.writers
+            .iter_mut()
+            .try_for_each(|writer| writer.write(buf))
+    }
+}
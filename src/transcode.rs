@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Conversion of the received data, e.g. of line endings
+
+use std::io::{self, Write};
+
+/// Converts data written in pieces, such as the subpackets of a transfer.
+pub trait Transcode {
+    /// Appends the conversion of `data` to `out`. Bytes whose conversion
+    /// depends on what follows can be held back until the next call.
+    fn transcode(&mut self, data: &[u8], out: &mut Vec<u8>);
+
+    /// Appends what is still held back at the end of the data.
+    fn finish(&mut self, _out: &mut Vec<u8>) {}
+}
+
+impl<F> Transcode for F
+where
+    F: FnMut(&[u8], &mut Vec<u8>),
+{
+    fn transcode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self(data, out)
+    }
+}
+
+/// Converts DOS line endings to LF. A CR which is not followed by LF is
+/// kept.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CrlfToLf {
+    // A CR ended the previous piece, and its LF may start the next:
+    cr: bool,
+}
+
+impl Transcode for CrlfToLf {
+    fn transcode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &byte in data {
+            if core::mem::take(&mut self.cr) && byte != b'\n' {
+                out.push(b'\r');
+            }
+            match byte {
+                b'\r' => self.cr = true,
+                _ => out.push(byte),
+            }
+        }
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if core::mem::take(&mut self.cr) {
+            out.push(b'\r');
+        }
+    }
+}
+
+/// Converts LF line endings to CRLF. An LF which already follows a CR is
+/// kept as is.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LfToCrlf {
+    cr: bool,
+}
+
+impl Transcode for LfToCrlf {
+    fn transcode(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        for &byte in data {
+            if byte == b'\n' && !self.cr {
+                out.push(b'\r');
+            }
+            out.push(byte);
+            self.cr = byte == b'\r';
+        }
+    }
+}
+
+/// A writer which converts the data with a `Transcode` before writing it to
+/// the inner writer, e.g. for receiving a text file with other line
+/// endings. The data is written with `write_all`, and so `inner` should
+/// block rather than fail with `WouldBlock`. As offsets change, it cannot be
+/// used with `read_seekable_with`.
+pub struct TranscodingWriter<W, T> {
+    inner: W,
+    transcode: T,
+    buf: Vec<u8>,
+}
+
+impl<W, T> TranscodingWriter<W, T>
+where
+    W: Write,
+    T: Transcode,
+{
+    pub fn new(inner: W, transcode: T) -> Self {
+        Self {
+            inner,
+            transcode,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Writes what `transcode` still holds back, and returns the inner
+    /// writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.buf.clear();
+        self.transcode.finish(&mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W, T> Write for TranscodingWriter<W, T>
+where
+    W: Write,
+    T: Transcode,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        self.transcode.transcode(buf, &mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,51 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//! Replaying recorded sessions
+
+use std::io::{self, Read, Write};
+
+/// A port which replays a recorded session. Reads are served from the bytes
+/// the peer originally sent, and writes are checked against the bytes that
+/// were originally sent back. A write that diverges from the recording fails
+/// with `io::ErrorKind::InvalidData`, which makes a transfer driven over the
+/// port fail at the first difference.
+pub struct TranscriptPort<'a> {
+    input: &'a [u8],
+    expected: &'a [u8],
+}
+
+impl<'a> TranscriptPort<'a> {
+    pub fn new(input: &'a [u8], expected: &'a [u8]) -> Self {
+        Self { input, expected }
+    }
+
+    /// Returns true if all of the input has been read and all of the
+    /// expected output has been written.
+    pub fn is_complete(&self) -> bool {
+        self.input.is_empty() && self.expected.is_empty()
+    }
+}
+
+impl Read for TranscriptPort<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.input.read(buf)
+    }
+}
+
+impl Write for TranscriptPort<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.expected.starts_with(buf) {
+            log::error!(
+                "Transcript diverged: {:?} != {:?}",
+                buf,
+                &self.expected[..buf.len().min(self.expected.len())]
+            );
+            return Err(io::ErrorKind::InvalidData.into());
+        }
+        self.expected = &self.expected[buf.len()..];
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
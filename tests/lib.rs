@@ -10,30 +10,178 @@ use std::process::*;
 use std::thread::{sleep, spawn};
 use std::time::*;
 
-struct InOut<R: Read, W: Write> {
-    r: R,
-    w: W,
+/// A port which reads back everything written to it.
+struct Loopback(std::collections::VecDeque<u8>);
+
+impl Read for Loopback {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for Loopback {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Records the bytes read from and written to the inner port.
+struct Recorder<P> {
+    port: P,
+    read: Vec<u8>,
+    written: Vec<u8>,
 }
 
-impl<R: Read, W: Write> InOut<R, W> {
-    pub fn new(r: R, w: W) -> InOut<R, W> {
-        InOut { r, w }
+impl<P> Recorder<P> {
+    pub fn new(port: P) -> Recorder<P> {
+        Recorder {
+            port,
+            read: Vec::new(),
+            written: Vec::new(),
+        }
     }
 }
 
-impl<R: Read, W: Write> Read for InOut<R, W> {
+impl<P: Read> Read for Recorder<P> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.r.read(buf)
+        let n = self.port.read(buf)?;
+        self.read.extend_from_slice(&buf[..n]);
+        Ok(n)
     }
 }
 
-impl<R: Read, W: Write> Write for InOut<R, W> {
+impl<P: Write> Write for Recorder<P> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.w.write(buf)
+        let n = self.port.write(buf)?;
+        self.written.extend_from_slice(&buf[..n]);
+        Ok(n)
     }
 
     fn flush(&mut self) -> Result<()> {
-        self.w.flush()
+        self.port.flush()
+    }
+}
+
+/// Parses the headers of the frames crossing the inner port in either
+/// direction, so that a test can check the exact sequence of frames.
+struct FrameRecorder<P> {
+    port: P,
+    read: Vec<u8>,
+    written: Vec<u8>,
+    // Direction (true when written) and end offset of each read and write:
+    crossings: Vec<(bool, usize)>,
+}
+
+impl<P> FrameRecorder<P> {
+    pub fn new(port: P) -> FrameRecorder<P> {
+        FrameRecorder {
+            port,
+            read: Vec::new(),
+            written: Vec::new(),
+            crossings: Vec::new(),
+        }
+    }
+
+    /// Returns the frames in the order in which their headers were
+    /// completed by a read or a write.
+    pub fn frames(&self) -> Vec<zmodem::Frame> {
+        let mut frames = vec![];
+        for (written, data) in [(false, &self.read), (true, &self.written)] {
+            for (end, kind) in parse_frames(data) {
+                let crossing = self
+                    .crossings
+                    .iter()
+                    .position(|&crossing| crossing.0 == written && crossing.1 >= end)
+                    .unwrap();
+                frames.push((crossing, end, kind));
+            }
+        }
+        frames.sort_by_key(|&(crossing, end, _)| (crossing, end));
+        frames.into_iter().map(|(_, _, kind)| kind).collect()
+    }
+}
+
+/// Returns the kind and end offset of each header in `data`, skipping the
+/// subpackets and anything else in between.
+fn parse_frames(data: &[u8]) -> Vec<(usize, zmodem::Frame)> {
+    const ZPAD_ZDLE: [u8; 2] = [b'*', 0x18];
+    let mut frames = vec![];
+    let mut offset = 0;
+    while let Some(pos) = data[offset..].windows(2).position(|w| w == ZPAD_ZDLE) {
+        let mut rest = &data[offset + pos + 2..];
+        match zmodem::Header::read(&mut rest) {
+            Ok(header) => {
+                offset = data.len() - rest.len();
+                frames.push((offset, header.kind()));
+            }
+            Err(_) => offset += pos + 1,
+        }
+    }
+    frames
+}
+
+impl<P: Read> Read for FrameRecorder<P> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.port.read(buf)?;
+        self.read.extend_from_slice(&buf[..n]);
+        self.crossings.push((false, self.read.len()));
+        Ok(n)
+    }
+}
+
+impl<P: Write> Write for FrameRecorder<P> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.port.write(buf)?;
+        self.written.extend_from_slice(&buf[..n]);
+        self.crossings.push((true, self.written.len()));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.port.flush()
+    }
+}
+
+/// A `Reader` returning at most 10 bytes per call, like a slow pipe.
+struct Trickle<'a>(Cursor<&'a [u8]>);
+
+impl zmodem::Reader for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<u32, zmodem::InvalidData> {
+        let len = buf.len().min(10);
+        match Read::read(&mut self.0, &mut buf[..len]) {
+            Ok(count) => Ok(count as u32),
+            Err(_) => Err(zmodem::InvalidData),
+        }
+    }
+
+    fn seek(&mut self, offset: u32) -> std::result::Result<u32, zmodem::InvalidData> {
+        self.0.set_position(offset as u64);
+        Ok(offset)
+    }
+}
+
+/// A `Reader` which fails past the given offset, like a bad disk.
+struct Failing<'a>(Cursor<&'a [u8]>, u64);
+
+impl zmodem::Reader for Failing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::result::Result<u32, zmodem::InvalidData> {
+        if self.0.position() >= self.1 {
+            return Err(zmodem::InvalidData);
+        }
+        let len = buf.len().min((self.1 - self.0.position()) as usize);
+        match Read::read(&mut self.0, &mut buf[..len]) {
+            Ok(count) => Ok(count as u32),
+            Err(_) => Err(zmodem::InvalidData),
+        }
+    }
+
+    fn seek(&mut self, offset: u32) -> std::result::Result<u32, zmodem::InvalidData> {
+        self.0.set_position(offset as u64);
+        Ok(offset)
     }
 }
 
@@ -47,6 +195,16 @@ lazy_static! {
     };
 }
 
+#[test]
+fn send_loopback() {
+    let mut port = Loopback(Default::default());
+    let mut c = Cursor::new(&RND_VALUES[..1024]);
+    assert_eq!(
+        zmodem::write(&mut port, &mut c, "test", None),
+        Err(zmodem::Error::Loopback)
+    );
+}
+
 #[test]
 #[cfg(unix)]
 fn recv_from_sz() {
@@ -62,7 +220,7 @@ fn recv_from_sz() {
 
     let child_stdin = sz.stdin.unwrap();
     let child_stdout = sz.stdout.unwrap();
-    let mut inout = InOut::new(child_stdout, child_stdin);
+    let mut inout = zmodem::Duplex::new(child_stdout, child_stdin);
 
     let mut c = Cursor::new(Vec::new());
     zmodem::read(&mut inout, &mut (None, 0), &mut c).unwrap();
@@ -86,7 +244,7 @@ fn send_to_rz() {
 
     let child_stdin = sz.stdin.unwrap();
     let child_stdout = sz.stdout.unwrap();
-    let mut inout = InOut::new(child_stdout, child_stdin);
+    let mut inout = zmodem::Duplex::new(child_stdout, child_stdin);
 
     let len = RND_VALUES.len() as u32;
     let copy = RND_VALUES.clone();
@@ -129,7 +287,7 @@ fn lib_send_recv() {
     spawn(move || {
         let outf = OpenOptions::new().write(true).open("test-fifo1").unwrap();
         let inf = File::open("test-fifo2").unwrap();
-        let mut inout = InOut::new(inf, outf);
+        let mut inout = zmodem::Duplex::new(inf, outf);
 
         let origin = RND_VALUES.clone();
         let mut c = Cursor::new(&origin);
@@ -141,7 +299,7 @@ fn lib_send_recv() {
 
     let inf = File::open("test-fifo1").unwrap();
     let outf = OpenOptions::new().write(true).open("test-fifo2").unwrap();
-    let mut inout = InOut::new(inf, outf);
+    let mut inout = zmodem::Duplex::new(inf, outf);
 
     zmodem::read(&mut inout, &mut (None, 0), &mut c).unwrap();
 
@@ -150,3 +308,1024 @@ fn lib_send_recv() {
 
     assert_eq!(RND_VALUES.clone(), c.into_inner());
 }
+
+#[test]
+#[cfg(all(unix, feature = "small"))]
+fn send_recv_small() {
+    use std::os::unix::net::UnixStream;
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..10000]);
+        zmodem::write(&mut tx, &mut c, "test", None).unwrap();
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    sender.join().unwrap();
+
+    assert!(RND_VALUES[..10000] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(all(unix, feature = "memmap2"))]
+fn send_recv_mmap() {
+    use std::os::unix::net::UnixStream;
+
+    let _ = remove_file("test-mmap");
+    File::create("test-mmap")
+        .unwrap()
+        .write_all(&RND_VALUES[..1024 * 1024])
+        .unwrap();
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let f = File::open("test-mmap").unwrap();
+        // SAFETY: the file is not modified until the sender is done.
+        let mut mmap = unsafe { zmodem::MmapReader::new(&f) }.unwrap();
+        let len = mmap.len();
+        zmodem::write(&mut tx, &mut mmap, "test-mmap", Some(len)).unwrap();
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    sender.join().unwrap();
+
+    let _ = remove_file("test-mmap");
+
+    assert!(RND_VALUES[..1024 * 1024] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_replay_transcript() {
+    use std::os::unix::net::UnixStream;
+
+    let (mut tx, rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..64 * 1024]);
+        zmodem::write(&mut tx, &mut c, "test", None).unwrap();
+    });
+
+    let mut recorder = Recorder::new(rx);
+    let mut live = Cursor::new(Vec::new());
+    zmodem::read(&mut recorder, &mut (None, 0), &mut live).unwrap();
+    sender.join().unwrap();
+
+    let mut port = zmodem::TranscriptPort::new(&recorder.read, &recorder.written);
+    let mut replay = Cursor::new(Vec::new());
+    zmodem::read(&mut port, &mut (None, 0), &mut replay).unwrap();
+    assert!(port.is_complete());
+    assert!(live.into_inner() == replay.into_inner());
+
+    let mut diverged = recorder.written.clone();
+    *diverged.last_mut().unwrap() ^= 0xff;
+    let mut port = zmodem::TranscriptPort::new(&recorder.read, &diverged);
+    let result = zmodem::read(&mut port, &mut (None, 0), &mut Cursor::new(Vec::new()));
+    assert_eq!(result, Err(zmodem::Error::InvalidData));
+}
+
+#[test]
+#[cfg(unix)]
+fn send_rate_limited() {
+    use std::os::unix::net::UnixStream;
+
+    const BITS_PER_SECOND: u64 = 1_000_000;
+    const LEN: usize = 32 * 1024;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut port = zmodem::RateLimitedPort::new(tx, BITS_PER_SECOND);
+        let mut c = Cursor::new(&RND_VALUES[..LEN]);
+        let start = Instant::now();
+        zmodem::write(&mut port, &mut c, "test", None).unwrap();
+        start.elapsed()
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    let elapsed = sender.join().unwrap();
+
+    assert!(RND_VALUES[..LEN] == c.into_inner()[..]);
+    // Escaping and framing add to the payload, which is the lower bound:
+    let expected = Duration::from_secs_f64((LEN * 8) as f64 / BITS_PER_SECOND as f64);
+    assert!(elapsed >= expected, "{:?} < {:?}", elapsed, expected);
+    assert!(
+        elapsed < expected * 2,
+        "{:?} >= {:?}",
+        elapsed,
+        expected * 2
+    );
+}
+
+#[test]
+#[cfg(unix)]
+fn send_short_reads() {
+    use std::os::unix::net::UnixStream;
+
+    const LEN: usize = 64 * 1024 + 123;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut recorder = Recorder::new(tx);
+        let mut trickle = Trickle(Cursor::new(&RND_VALUES[..LEN]));
+        zmodem::write(&mut recorder, &mut trickle, "test", None).unwrap();
+        recorder.written
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    let written = sender.join().unwrap();
+
+    assert!(RND_VALUES[..LEN] == c.into_inner()[..]);
+    // Short reads do not cut the windows of 10 subpackets short. With the
+    // small feature the receiver advertises a buffer of one subpacket, which
+    // is then the window:
+    let window = if cfg!(feature = "small") {
+        256
+    } else {
+        1024 * 10
+    };
+    let zdata = [b'*', 0x18, b'C', 10];
+    let frames = written.windows(4).filter(|w| *w == zdata).count();
+    assert!(frames <= LEN / window + 1, "{} ZDATA frames", frames);
+}
+
+#[rstest::rstest]
+#[case("zmodem-send_file-missing")]
+#[case(".")]
+fn send_file_unavailable(#[case] name: &str) {
+    let path = std::env::temp_dir().join(name);
+    let mut port = Loopback(std::collections::VecDeque::new());
+    let result = zmodem::send_file(&mut port, &path);
+    assert_eq!(result, Err(zmodem::Error::SourceUnavailable));
+    // Nothing has been sent:
+    assert!(port.0.is_empty());
+}
+
+#[test]
+#[cfg(unix)]
+fn send_file_to_read() {
+    use std::os::unix::net::UnixStream;
+
+    let path = std::env::temp_dir().join("zmodem-send_file");
+    File::create(&path)
+        .unwrap()
+        .write_all(&RND_VALUES[..64 * 1024])
+        .unwrap();
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = {
+        let path = path.clone();
+        spawn(move || zmodem::send_file(&mut tx, &path).unwrap())
+    };
+
+    let mut c = Cursor::new(Vec::new());
+    let mut state = (None, 0);
+    zmodem::read(&mut rx, &mut state, &mut c).unwrap();
+    sender.join().unwrap();
+
+    let _ = remove_file(&path);
+
+    let info = state.0.unwrap().info().clone();
+    assert_eq!(info.name, "zmodem-send_file");
+    assert_eq!(info.size, Some(64 * 1024));
+    assert!(RND_VALUES[..64 * 1024] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn write_to_recv_file() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join("zmodem-recv_file");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..64 * 1024]);
+        zmodem::write(&mut tx, &mut c, "../test", Some(64 * 1024)).unwrap();
+    });
+
+    let paths = zmodem::recv_file(&mut rx, &dir).unwrap();
+    sender.join().unwrap();
+
+    assert_eq!(paths, vec![dir.join("test")]);
+    let received = std::fs::read(&paths[0]).unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(RND_VALUES[..64 * 1024] == received[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_file_rename() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join("zmodem-recv_file_rename");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    let config = zmodem::Config {
+        on_exists: zmodem::ExistsPolicy::Rename,
+        ..Default::default()
+    };
+
+    for (i, name) in ["data.bin", "data (1).bin"].iter().enumerate() {
+        let (mut tx, mut rx) = UnixStream::pair().unwrap();
+        let sender = spawn(move || {
+            let mut c = Cursor::new(&RND_VALUES[i * 1024..(i + 1) * 1024]);
+            zmodem::write(&mut tx, &mut c, "data.bin", Some(1024)).unwrap();
+        });
+        let paths = zmodem::recv_file_with(&mut rx, &dir, &config).unwrap();
+        sender.join().unwrap();
+
+        assert_eq!(paths, vec![dir.join(name)]);
+        let received = std::fs::read(&paths[0]).unwrap();
+        assert!(RND_VALUES[i * 1024..(i + 1) * 1024] == received[..]);
+    }
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_file_skip() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join("zmodem-recv_file_skip");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("data.bin"), b"existing").unwrap();
+    let config = zmodem::Config {
+        on_exists: zmodem::ExistsPolicy::Skip,
+        ..Default::default()
+    };
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..1024]);
+        zmodem::write(&mut tx, &mut c, "data.bin", Some(1024)).unwrap();
+    });
+    let paths = zmodem::recv_file_with(&mut rx, &dir, &config).unwrap();
+    sender.join().unwrap();
+
+    let existing = std::fs::read(dir.join("data.bin")).unwrap();
+    let count = std::fs::read_dir(&dir).unwrap().count();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(paths.is_empty());
+    assert_eq!(existing, b"existing");
+    assert_eq!(count, 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_file_resume() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join("zmodem-recv_file_resume");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+    std::fs::write(dir.join("data.bin"), &RND_VALUES[..1000]).unwrap();
+    let config = zmodem::Config {
+        on_exists: zmodem::ExistsPolicy::Resume,
+        ..Default::default()
+    };
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..4096]);
+        zmodem::write(&mut tx, &mut c, "data.bin", Some(4096)).unwrap()
+    });
+    let paths = zmodem::recv_file_with(&mut rx, &dir, &config).unwrap();
+    let stats = sender.join().unwrap();
+
+    let received = std::fs::read(dir.join("data.bin")).unwrap();
+    let count = std::fs::read_dir(&dir).unwrap().count();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert_eq!(paths, vec![dir.join("data.bin")]);
+    // Only the rest of the file is sent:
+    assert_eq!(stats.data_bytes, 4096 - 1000);
+    assert!(RND_VALUES[..4096] == received[..]);
+    assert_eq!(count, 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_fixed_subpacket_len() {
+    use std::os::unix::net::UnixStream;
+
+    let len = 64 * 1024 + 100;
+    let config = zmodem::Config {
+        fixed_subpacket_len: true,
+        ..Default::default()
+    };
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = {
+        let config = config.clone();
+        spawn(move || {
+            let mut c = Cursor::new(&RND_VALUES[..len]);
+            zmodem::write_with(&mut tx, &mut c, "test", Some(len as u32), &config).unwrap();
+        })
+    };
+
+    let mut c = Cursor::new(Vec::new());
+    let mut state = (None, 0);
+    zmodem::read_with(&mut rx, &mut state, &mut c, &config).unwrap();
+    sender.join().unwrap();
+
+    assert_eq!(state.1, len as u32);
+    assert!(RND_VALUES[..len] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_cancel() {
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    let config = zmodem::Config {
+        cancel: Some(cancel.clone()),
+        ..Default::default()
+    };
+    // Stops the sender once the receiver is gone:
+    let cancel_sender = Arc::new(AtomicBool::new(false));
+    let sender_config = zmodem::Config {
+        cancel: Some(cancel_sender.clone()),
+        ..Default::default()
+    };
+
+    let (mut tx, rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..]);
+        zmodem::write_with(&mut tx, &mut c, "test", None, &sender_config)
+    });
+    let canceler = spawn(move || {
+        sleep(Duration::from_millis(100));
+        cancel.store(true, Ordering::Relaxed);
+    });
+
+    let start = Instant::now();
+    let mut recorder = Recorder::new(rx);
+    let mut c = Cursor::new(Vec::new());
+    let result = zmodem::read_with(&mut recorder, &mut (None, 0), &mut c, &config);
+    let elapsed = start.elapsed();
+    canceler.join().unwrap();
+    drop(recorder.port);
+    cancel_sender.store(true, Ordering::Relaxed);
+    assert!(sender.join().unwrap().is_err());
+
+    assert_eq!(result.map(|_| ()), Err(zmodem::Error::Canceled));
+    assert!(elapsed < Duration::from_secs(5));
+    assert!(c.into_inner().len() < RND_VALUES.len());
+    let mut abort = vec![0x18; 10];
+    abort.extend_from_slice(&[0x08; 10]);
+    assert!(recorder.written.ends_with(&abort));
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_file_concurrent() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join("zmodem-recv_file_concurrent");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+
+    // Both receivers write their partial files to the same directory:
+    let threads: Vec<_> = ["a.bin", "b.bin"]
+        .into_iter()
+        .enumerate()
+        .map(|(i, name)| {
+            let (mut tx, mut rx) = UnixStream::pair().unwrap();
+            let data = &RND_VALUES[i * 65536..(i + 1) * 65536];
+            let sender = spawn(move || {
+                let mut c = Cursor::new(data);
+                zmodem::write(&mut tx, &mut c, name, Some(65536)).unwrap();
+            });
+            let dir = dir.clone();
+            // The port is kept open until the sender is done with it:
+            let receiver = spawn(move || (zmodem::recv_file(&mut rx, &dir).unwrap(), rx));
+            (sender, receiver)
+        })
+        .collect();
+    for (i, (sender, receiver)) in threads.into_iter().enumerate() {
+        sender.join().unwrap();
+        let (paths, _) = receiver.join().unwrap();
+        let received = std::fs::read(&paths[0]).unwrap();
+        assert!(RND_VALUES[i * 65536..(i + 1) * 65536] == received[..]);
+    }
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+#[cfg(unix)]
+fn recv_file_keep_partial() {
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join("zmodem-keep_partial");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir(&dir).unwrap();
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut failing = Failing(Cursor::new(&RND_VALUES[..64 * 1024]), 4096);
+        zmodem::write(&mut tx, &mut failing, "test", Some(64 * 1024))
+    });
+
+    let config = zmodem::Config {
+        keep_partial_on_error: true,
+        ..Default::default()
+    };
+    let result = zmodem::recv_file_with(&mut rx, &dir, &config);
+    assert!(sender.join().unwrap().is_err());
+
+    let partial = std::fs::read(dir.join("test.part"));
+    let count = std::fs::read_dir(&dir).unwrap().count();
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let err = result.unwrap_err();
+    assert_eq!(err.error, zmodem::Error::InvalidData);
+    assert_eq!(err.partial, Some(dir.join("test.part")));
+    // The sender reads ahead, and so may fail before sending all of the
+    // data it could read:
+    let partial = partial.unwrap();
+    assert!(!partial.is_empty() && partial.len() <= 4096);
+    assert!(RND_VALUES.starts_with(&partial));
+    assert_eq!(count, 1);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_small_buffer() {
+    use std::os::unix::net::UnixStream;
+
+    const LEN: usize = 64 * 1024;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut recorder = Recorder::new(tx);
+        let mut c = Cursor::new(&RND_VALUES[..LEN]);
+        zmodem::write(&mut recorder, &mut c, "test", None).unwrap();
+        recorder.written
+    });
+
+    let config = zmodem::Config {
+        buffer_len: Some(256),
+        ..Default::default()
+    };
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read_with(&mut rx, &mut (None, 0), &mut c, &config).unwrap();
+    let written = sender.join().unwrap();
+
+    assert!(RND_VALUES[..LEN] == c.into_inner()[..]);
+    // One ZCRCW per 256 bytes, and one for ZFILE:
+    let zcrcw = [0x18, b'k'];
+    let acks = written.windows(2).filter(|w| *w == zcrcw).count();
+    assert_eq!(acks, LEN / 256 + 1);
+}
+
+#[test]
+#[cfg(feature = "lossy-link")]
+fn send_recv_lossy_link() {
+    use std::time::Duration;
+
+    const LEN: usize = 4096;
+
+    let options = zmodem::LinkOptions {
+        drop: 0.01,
+        latency: Duration::from_millis(1),
+        timeout: Some(Duration::from_millis(20)),
+        seed: 1,
+        ..Default::default()
+    };
+    let (mut tx, mut rx) = zmodem::LossyLink::pair(options);
+
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..LEN]);
+        let stats = zmodem::write(&mut tx, &mut c, "test", Some(LEN as u32));
+        (stats, tx)
+    });
+
+    // Short subpackets get through more often:
+    let config = zmodem::Config {
+        buffer_len: Some(64),
+        ..Default::default()
+    };
+    let mut c = Cursor::new(Vec::new());
+    let stats = zmodem::read_with(&mut rx, &mut (None, 0), &mut c, &config).unwrap();
+    drop(rx);
+    let (result, _) = sender.join().unwrap();
+    result.unwrap();
+
+    assert!(stats.retransmits > 0);
+    assert!(RND_VALUES[..LEN] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_compute_crc() {
+    use std::os::unix::net::UnixStream;
+
+    const LEN: usize = 10000;
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let config = zmodem::Config {
+        compute_crc: true,
+        ..Default::default()
+    };
+
+    let sender_config = config.clone();
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..LEN]);
+        zmodem::write_with(&mut tx, &mut c, "test", None, &sender_config).unwrap()
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    let received = zmodem::read_with(&mut rx, &mut (None, 0), &mut c, &config).unwrap();
+    let sent = sender.join().unwrap();
+
+    let expected = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC).checksum(&RND_VALUES[..LEN]);
+    assert_eq!(sent.crc32, Some(expected));
+    assert_eq!(received.crc32, Some(expected));
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_dialect() {
+    use std::os::unix::net::UnixStream;
+
+    const LEN: usize = 10000;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+    let config = zmodem::Config {
+        dialect: zmodem::Dialect {
+            zpad: b'#',
+            zdle: 0x10,
+        },
+        ..Default::default()
+    };
+
+    let sender_config = config.clone();
+    let sender = spawn(move || {
+        let mut recorder = Recorder::new(tx);
+        let mut c = Cursor::new(&RND_VALUES[..LEN]);
+        zmodem::write_with(&mut recorder, &mut c, "test", None, &sender_config).unwrap();
+        recorder.written
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read_with(&mut rx, &mut (None, 0), &mut c, &config).unwrap();
+    let written = sender.join().unwrap();
+
+    assert!(RND_VALUES[..LEN] == c.into_inner()[..]);
+    // The standard ZDLE is escaped like any other byte:
+    assert!(!written.contains(&0x18));
+    assert!(written.starts_with(b"##\x10B"));
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_channel() {
+    use std::os::unix::net::UnixStream;
+    use std::sync::mpsc::channel;
+
+    const LEN: usize = 100_000;
+
+    let (chunks, receiver) = channel();
+    let producer = spawn(move || {
+        // Chunks of all sizes, which do not line up with the subpackets:
+        let mut offset = 0;
+        for len in (1..).map(|n| n * 97 % 3001) {
+            let end = LEN.min(offset + len);
+            chunks.send(RND_VALUES[offset..end].to_vec()).unwrap();
+            offset = end;
+            if offset == LEN {
+                break;
+            }
+            sleep(Duration::from_micros(50));
+        }
+    });
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut reader = zmodem::ChannelReader::new(receiver, 64 * 1024);
+        zmodem::write(&mut tx, &mut reader, "test", None).unwrap()
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    sender.join().unwrap();
+    producer.join().unwrap();
+    assert!(RND_VALUES[..LEN] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_crlf_to_lf() {
+    use std::os::unix::net::UnixStream;
+
+    let subpacket_size = if cfg!(feature = "small") { 256 } else { 1024 };
+    let mut data = b"line\r\n".repeat(subpacket_size);
+    // A CRLF split between two subpackets, a CR on its own, and a CR at the
+    // end of the file:
+    data[subpacket_size - 1..subpacket_size + 1].copy_from_slice(b"\r\n");
+    data[subpacket_size * 2 + 1] = b'\r';
+    data.push(b'\r');
+    let expected = String::from_utf8(data.clone())
+        .unwrap()
+        .replace("\r\n", "\n")
+        .into_bytes();
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&data[..]);
+        zmodem::write(&mut tx, &mut c, "test", None).unwrap()
+    });
+
+    let mut out = zmodem::TranscodingWriter::new(Vec::new(), zmodem::CrlfToLf::default());
+    zmodem::read(&mut rx, &mut (None, 0), &mut out).unwrap();
+    sender.join().unwrap();
+    assert_eq!(out.finish().unwrap(), expected);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_multi_writer() {
+    use std::os::unix::net::UnixStream;
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..65536]);
+        zmodem::write(&mut tx, &mut c, "test", Some(65536)).unwrap()
+    });
+
+    let path = std::env::temp_dir().join("zmodem-multi_writer");
+    let mut file = File::create(&path).unwrap();
+    let mut buf = Vec::new();
+    let writers: Vec<&mut dyn zmodem::Writer> = vec![&mut file, &mut buf];
+    let mut out = zmodem::MultiWriter::new(writers);
+    zmodem::read(&mut rx, &mut (None, 0), &mut out).unwrap();
+    sender.join().unwrap();
+    drop(file);
+
+    assert_eq!(buf, RND_VALUES[..65536]);
+    assert_eq!(std::fs::read(&path).unwrap(), RND_VALUES[..65536]);
+    remove_file(&path).unwrap();
+}
+
+/// Cipher XORing the data with a keystream derived from the offset
+#[cfg(feature = "crypto")]
+struct Xor(u8);
+
+#[cfg(feature = "crypto")]
+impl zmodem::Cipher for Xor {
+    fn encrypt(&mut self, offset: u32, data: &mut [u8]) {
+        for (i, b) in data.iter_mut().enumerate() {
+            *b ^= self.0 ^ (offset as usize + i) as u8;
+        }
+    }
+
+    fn decrypt(&mut self, offset: u32, data: &mut [u8]) {
+        self.encrypt(offset, data)
+    }
+}
+
+#[test]
+#[cfg(all(unix, feature = "crypto"))]
+fn send_recv_crypto() {
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+
+    let data = b"attack at dawn ".repeat(1000);
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender_data = data.clone();
+    let sender = spawn(move || {
+        let config = zmodem::Config {
+            cipher: Some(Arc::new(Mutex::new(Xor(0x5a)))),
+            ..Default::default()
+        };
+        let mut recorder = Recorder::new(tx);
+        let mut c = Cursor::new(sender_data);
+        zmodem::write_with(&mut recorder, &mut c, "test", None, &config).unwrap();
+        recorder.written
+    });
+
+    let config = zmodem::Config {
+        cipher: Some(Arc::new(Mutex::new(Xor(0x5a)))),
+        ..Default::default()
+    };
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read_with(&mut rx, &mut (None, 0), &mut c, &config).unwrap();
+    let written = sender.join().unwrap();
+
+    assert!(c.into_inner() == data);
+    assert!(!written.windows(15).any(|w| w == b"attack at dawn "));
+}
+
+#[test]
+#[cfg(all(unix, feature = "crypto"))]
+fn send_crypto_unsupported() {
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let config = zmodem::Config {
+            cipher: Some(Arc::new(Mutex::new(Xor(0x5a)))),
+            ..Default::default()
+        };
+        let mut c = Cursor::new(&RND_VALUES[..100]);
+        zmodem::write_with(&mut tx, &mut c, "test", None, &config)
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    let _ = zmodem::read(&mut rx, &mut (None, 0), &mut c);
+    let result = sender.join().unwrap();
+    assert_eq!(result, Err(zmodem::Error::EncryptionUnsupported));
+    assert!(c.into_inner().is_empty());
+}
+
+#[rstest::rstest]
+#[case(0, 0)]
+#[case(1, 0)]
+#[case(10, 0)]
+#[case(10, -1)]
+#[case(10, 1)]
+#[cfg(unix)]
+fn send_recv_subpacket_multiple(#[case] subpackets: usize, #[case] extra: isize) {
+    use std::os::unix::net::UnixStream;
+
+    let subpacket_size = if cfg!(feature = "small") { 256 } else { 1024 };
+    let len = (subpacket_size * subpackets) as isize + extra;
+    let len = len as usize;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn(move || {
+        let mut recorder = Recorder::new(tx);
+        let mut c = Cursor::new(&RND_VALUES[..len]);
+        let stats = zmodem::write(&mut recorder, &mut c, "test", Some(len as u32)).unwrap();
+        (recorder.written, stats)
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    let received = zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    let (written, sent) = sender.join().unwrap();
+
+    assert!(RND_VALUES[..len] == c.into_inner()[..]);
+    let expected = zmodem::subpacket_count(len as u64, &zmodem::Config::default()).unwrap();
+    assert_eq!(expected, len.div_ceil(subpacket_size) as u64);
+    assert_eq!(sent.subpackets_sent, expected);
+    assert_eq!(received.subpackets_received, expected);
+    // No empty subpacket is sent, only the ones with data and ZFILE's:
+    let count = |end: u8| written.windows(2).filter(|w| *w == [0x18, end]).count();
+    let zcrcg = count(b'i');
+    let zcrcw = count(b'k');
+    assert_eq!(zcrcg + zcrcw, len.div_ceil(subpacket_size) + 1);
+}
+
+#[rstest::rstest]
+// Text needs no escaping, only the CRCs add to it:
+#[case(b'a', 1.0, 1.1)]
+// Every ZDLE is escaped with another byte:
+#[case(0x18, 1.9, 2.1)]
+#[cfg(unix)]
+fn send_recv_compression_ratio(#[case] byte: u8, #[case] min: f64, #[case] max: f64) {
+    use std::os::unix::net::UnixStream;
+
+    let data = vec![byte; 10_000];
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+
+    let sender = spawn({
+        let data = data.clone();
+        move || {
+            let mut tx = tx;
+            let mut c = Cursor::new(&data);
+            zmodem::write(&mut tx, &mut c, "test", Some(data.len() as u32)).unwrap()
+        }
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    let stats = sender.join().unwrap();
+
+    assert_eq!(c.into_inner(), data);
+    assert_eq!(stats.data_bytes, data.len() as u64);
+    let ratio = stats.compression_ratio().unwrap();
+    assert!(min < ratio && ratio < max, "{}", ratio);
+}
+
+/// A bounded buffer between the receiver and a consumer, whose writes fail
+/// with `WouldBlock` when it is full.
+struct Ring {
+    queue: std::sync::Arc<std::sync::Mutex<std::collections::VecDeque<u8>>>,
+    capacity: usize,
+}
+
+impl Write for Ring {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut queue = self.queue.lock().unwrap();
+        let count = buf.len().min(self.capacity - queue.len());
+        if count == 0 {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        queue.extend(&buf[..count]);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_slow_consumer() {
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+
+    const LEN: usize = 64 * 1024;
+    const CAPACITY: usize = 4096;
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut c = Cursor::new(&RND_VALUES[..LEN]);
+        zmodem::write(&mut tx, &mut c, "test", Some(LEN as u32)).unwrap()
+    });
+
+    let queue = Arc::new(Mutex::new(std::collections::VecDeque::new()));
+    let consumer = spawn({
+        let queue = queue.clone();
+        move || {
+            let mut data = Vec::new();
+            while data.len() < LEN {
+                {
+                    let mut queue = queue.lock().unwrap();
+                    assert!(queue.len() <= CAPACITY);
+                    let count = queue.len().min(512);
+                    data.extend(queue.drain(..count));
+                }
+                sleep(Duration::from_millis(1));
+            }
+            data
+        }
+    });
+
+    let config = zmodem::Config {
+        backoff: Some(Arc::new(Mutex::new(zmodem::Fixed(Duration::from_millis(
+            1,
+        ))))),
+        ..Default::default()
+    };
+    let mut ring = Ring {
+        queue,
+        capacity: CAPACITY,
+    };
+    let stats = zmodem::read_with(&mut rx, &mut (None, 0), &mut ring, &config).unwrap();
+    sender.join().unwrap();
+
+    assert!(RND_VALUES[..LEN] == consumer.join().unwrap()[..]);
+    // The sender was held back by asking for the data again:
+    assert!(stats.retransmits > 0);
+}
+
+fn assert_reader<R: zmodem::Reader>() {}
+
+fn assert_writer<W: zmodem::Writer>() {}
+
+#[test]
+fn reader_writer_by_reference() {
+    assert_reader::<&mut File>();
+    assert_reader::<&mut Cursor<Vec<u8>>>();
+    assert_writer::<&mut File>();
+    assert_writer::<&mut Vec<u8>>();
+}
+
+#[test]
+fn capabilities() {
+    let capabilities = zmodem::capabilities();
+    assert_eq!(capabilities.crypto, cfg!(feature = "crypto"));
+    assert_eq!(capabilities.mmap, cfg!(feature = "memmap2"));
+    assert_eq!(capabilities.lossy_link, cfg!(feature = "lossy-link"));
+    assert_eq!(
+        capabilities.zrinit.contains(zmodem::Zrinit::CANCRY),
+        cfg!(feature = "crypto")
+    );
+    let subpacket_size = if cfg!(feature = "small") { 256 } else { 1024 };
+    assert_eq!(capabilities.subpacket_size, subpacket_size);
+    assert!(capabilities.encodings.contains(&zmodem::Encoding::ZBIN32));
+    assert_eq!(zmodem::version(), env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_frame_sequence() {
+    use std::os::unix::net::UnixStream;
+    use zmodem::Frame::*;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut port = FrameRecorder::new(tx);
+        let mut c = Cursor::new(&RND_VALUES[..100]);
+        zmodem::write(&mut port, &mut c, "test", Some(100)).unwrap();
+        port.frames()
+    });
+
+    let mut c = Cursor::new(Vec::new());
+    zmodem::read(&mut rx, &mut (None, 0), &mut c).unwrap();
+    let frames = sender.join().unwrap();
+    // The receiver sends ZRINIT when it starts and again for ZRQINIT, and
+    // acknowledges the only subpacket, which ends with ZCRCW:
+    assert_eq!(
+        frames,
+        [ZRQINIT, ZRINIT, ZFILE, ZRINIT, ZRPOS, ZDATA, ZACK, ZEOF, ZRINIT, ZFIN, ZFIN]
+    );
+    assert!(RND_VALUES[..100] == c.into_inner()[..]);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_batch_frames() {
+    use std::os::unix::net::UnixStream;
+    use zmodem::Frame::*;
+
+    let (tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut port = FrameRecorder::new(tx);
+        let mut a = Cursor::new(&RND_VALUES[..100]);
+        let mut b = Cursor::new(&RND_VALUES[100..200]);
+        let files = [("a", Some(100), &mut a), ("b", Some(100), &mut b)];
+        zmodem::write_batch(&mut port, files, &zmodem::Config::default()).unwrap();
+        port.frames()
+    });
+
+    let files = zmodem::read_batch(&mut rx, |_| Some(vec![]), &zmodem::Config::default());
+    let frames = sender.join().unwrap();
+    // The ZRINIT after the ZEOF of "a" is answered with the ZFILE of "b",
+    // and only the one after "b" with ZFIN:
+    assert_eq!(
+        frames,
+        [
+            ZRQINIT, ZRINIT, ZFILE, ZRINIT, ZRPOS, ZDATA, ZACK, ZEOF, ZRINIT, ZFILE, ZRPOS, ZDATA,
+            ZACK, ZEOF, ZRINIT, ZFIN, ZFIN
+        ]
+    );
+    assert_eq!(files.unwrap().len(), 2);
+}
+
+#[test]
+#[cfg(unix)]
+fn send_recv_batch() {
+    use std::os::unix::net::UnixStream;
+
+    let (mut tx, mut rx) = UnixStream::pair().unwrap();
+    let sender = spawn(move || {
+        let mut a = Cursor::new(&RND_VALUES[..5000]);
+        let mut b = Cursor::new(&RND_VALUES[5000..6000]);
+        let mut c = Cursor::new(&RND_VALUES[6000..8000]);
+        let files = [
+            ("a", Some(5000), &mut a),
+            ("b", Some(1000), &mut b),
+            ("c", Some(2000), &mut c),
+        ];
+        zmodem::write_batch(&mut tx, files, &zmodem::Config::default()).unwrap()
+    });
+
+    let mut names = vec![];
+    let accept = |file: &zmodem::File| {
+        names.push(file.name().to_string());
+        (file.name() != "b").then(Vec::new)
+    };
+    let files = zmodem::read_batch(&mut rx, accept, &zmodem::Config::default()).unwrap();
+    let stats = sender.join().unwrap();
+
+    assert_eq!(names, ["a", "b", "c"]);
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].0.name(), "a");
+    assert!(files[0].1 == zmodem::FileOutcome::Received(RND_VALUES[..5000].to_vec()));
+    assert_eq!(files[1].0.name(), "c");
+    assert!(files[1].1 == zmodem::FileOutcome::Received(RND_VALUES[6000..8000].to_vec()));
+    assert_eq!(stats.peer_received_bytes, Some(7000));
+    assert_eq!(stats.completion, zmodem::Completion::Clean);
+}